@@ -1,93 +1,186 @@
-use crate::users::add_user_to_file;
-use crate::log::log_message;
-use std::collections::HashMap;
-use tokio::sync::Mutex;
-use std::sync::Arc;
-use colored::Color;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use crate::users::{add_user_to_file, is_valid_nick, update_user_in_file, MAX_NICK_LEN};
+use crate::password::{hash_password, verify_password, PasswordCheck};
+use crate::log::{log_message, LogLevel};
+use crate::event::{PromptField, ServerEvent};
+use crate::protocol::{ClientCommand, ClientReader, ClientWriter, ProtocolMode, JSON_MODE_SWITCH};
+use crate::server::ServerState;
+use std::error::Error;
+use std::fmt;
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::watch;
+
+// Argon2 намеренно медленный, поэтому считается вне потоков рантайма.
+async fn hash_blocking(password: String) -> Result<String, Box<dyn Error + Send + Sync>> {
+    Ok(tokio::task::spawn_blocking(move || hash_password(&password)).await??)
+}
+
+async fn verify_blocking(stored: String, password: String) -> Result<PasswordCheck, Box<dyn Error + Send + Sync>> {
+    Ok(tokio::task::spawn_blocking(move || verify_password(&stored, &password)).await?)
+}
+
+// Пароль из старого users.txt хранится в открытом виде: после успешного входа заменяем его хешем.
+async fn upgrade_legacy_password(
+    state: &ServerState,
+    nick: &str,
+    legacy: &str,
+    password: String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let password_hash = hash_blocking(password).await?;
+    let mut db_guard = state.users_db.lock().await;
+    // Запись могла измениться, пока считался хеш.
+    if db_guard.get(nick).map(String::as_str) != Some(legacy) {
+        return Ok(());
+    }
+    db_guard.insert(nick.to_string(), password_hash.clone());
+    update_user_in_file(&state.config().users_file, nick, &password_hash).await?;
+    drop(db_guard);
+    log_message(LogLevel::Info, "auth", &format!("Пароль пользователя '{}' перехеширован в Argon2id.", nick));
+    Ok(())
+}
+
+// Сервер остановился, пока клиент проходил авторизацию; уведомление клиенту уже отправлено.
+#[derive(Debug)]
+pub struct ShuttingDown;
+
+impl fmt::Display for ShuttingDown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("сервер останавливается")
+    }
+}
+
+impl Error for ShuttingDown {}
+
+// Читает ответ на приглашение. В режиме JSON ответ должен иметь вид {"cmd":"answer","value":"..."}.
+// None означает, что клиент закрыл соединение.
+async fn read_answer(
+    reader: &mut ClientReader,
+    writer: &ClientWriter,
+    shutdown: &mut watch::Receiver<bool>,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    loop {
+        let mut line = String::new();
+        // Клиент ещё не в connected_users, поэтому об остановке сервера узнаёт отсюда.
+        let read = tokio::select! {
+            read = reader.read_line(&mut line) => read?,
+            // Ссылка на значение не держится через await отправки: с ней future не Send.
+            _ = async { drop(shutdown.wait_for(|stopping| *stopping).await) } => {
+                writer.send(&ServerEvent::server_shutdown()).await?;
+                return Err(ShuttingDown.into());
+            }
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if writer.mode() == ProtocolMode::Text {
+            return Ok(Some(line.to_string()));
+        }
+        match ClientCommand::parse_json(line) {
+            Ok(ClientCommand::Answer { value }) => return Ok(Some(value.trim().to_string())),
+            _ => {
+                writer.send(&ServerEvent::error("expected_answer", "Ожидался ответ вида {\"cmd\":\"answer\",\"value\":\"...\"}.")).await?;
+            }
+        }
+    }
+}
 
 pub async fn authorize_user(
-    reader: &mut BufReader<OwnedReadHalf>,
-    writer: &Arc<Mutex<OwnedWriteHalf>>,
-    users_db: Arc<Mutex<HashMap<String, String>>>,
-) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    reader: &mut ClientReader,
+    writer: &ClientWriter,
+    state: &ServerState,
+    shutdown: &mut watch::Receiver<bool>,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
     let mut attempts = 3;
     loop {
         if attempts == 0 {
-            let mut writer_guard = writer.lock().await;
-            writer_guard.write_all("Превышено количество попыток. Отключение.\n".as_bytes()).await?;
-            writer_guard.flush().await?;
-            log_message("Auth", "Неудачная авторизация: Превышено количество попыток.", Color::Red).await?;
+            writer.send(&ServerEvent::error("auth_attempts_exceeded", "Превышено количество попыток. Отключение.")).await?;
+            log_message(LogLevel::Warn, "auth", "Неудачная авторизация: Превышено количество попыток.");
             return Err("Неудачная авторизация".into());
         }
 
-        let mut nick_input = String::new();
-        {
-            let mut writer_guard = writer.lock().await;
-            writer_guard.write_all("Введите никнейм:\n".as_bytes()).await?;
-            writer_guard.flush().await?;
-        }
-        if reader.read_line(&mut nick_input).await? == 0 {
-            log_message("Client", "Клиент отключился до авторизации (ввод никнейма).", Color::Yellow).await?;
+        writer.send(&ServerEvent::Prompt { field: PromptField::Nick }).await?;
+        let Some(nick_input) = read_answer(reader, writer, shutdown).await? else {
+            log_message(LogLevel::Info, "client", "Клиент отключился до авторизации (ввод никнейма).");
             return Err("Клиент отключился до авторизации".into());
+        };
+
+        if writer.mode() == ProtocolMode::Text && nick_input == JSON_MODE_SWITCH {
+            writer.set_mode(ProtocolMode::Json);
+            writer.send(&ServerEvent::ModeChanged { protocol: "json".to_string() }).await?;
+            // Текстовое приветствие клиент пропускает, поэтому оно повторяется событием.
+            writer.send(&ServerEvent::Welcome { text: state.config().welcome.clone() }).await?;
+            log_message(LogLevel::Info, "auth", "Клиент перешёл в режим JSON Lines.");
+            continue;
         }
-        let nick_input = nick_input.trim().to_string();
 
-        let mut pass_input = String::new();
-        {
-            let mut writer_guard = writer.lock().await;
-            writer_guard.write_all("Введите пароль:\n".as_bytes()).await?;
-            writer_guard.flush().await?;
+        if !is_valid_nick(&nick_input) {
+            writer.send(&ServerEvent::error("invalid_nick", format!("Никнейм должен быть непустым, до {} символов, без пробелов, управляющих символов и ':'.", MAX_NICK_LEN))).await?;
+            attempts -= 1;
+            log_message(LogLevel::Info, "auth", &format!("Отклонён недопустимый никнейм. Осталось попыток: {}", attempts));
+            continue;
         }
-        if reader.read_line(&mut pass_input).await? == 0 {
-            log_message("Client", "Клиент отключился до авторизации (ввод пароля).", Color::Yellow).await?;
-            return Err("Клиент отключился до авторизации".into());
+
+        let ban = state.moderation.lock().await.ban_of(&nick_input).cloned();
+        if let Some(ban) = ban {
+            writer.send(&ServerEvent::Banned { by: ban.by, reason: ban.reason }).await?;
+            log_message(LogLevel::Warn, "auth", &format!("Заблокированный пользователь '{}' пытался войти.", nick_input));
+            return Err("Пользователь заблокирован".into());
         }
-        let pass_input = pass_input.trim().to_string();
 
-        let mut db_guard = users_db.lock().await;
-        match db_guard.get(&nick_input) {
-            Some(stored_pass) if *stored_pass == pass_input => {
-                let mut writer_guard = writer.lock().await;
-                writer_guard.write_all("Авторизация успешна!\n".as_bytes()).await?;
-                writer_guard.flush().await?;
-                log_message("Auth", &format!("Пользователь '{}' авторизовался успешно.", nick_input), Color::Green).await?;                
+        writer.send(&ServerEvent::Prompt { field: PromptField::Password }).await?;
+        let Some(pass_input) = read_answer(reader, writer, shutdown).await? else {
+            log_message(LogLevel::Info, "client", "Клиент отключился до авторизации (ввод пароля).");
+            return Err("Клиент отключился до авторизации".into());
+        };
+
+        let stored_pass = state.users_db.lock().await.get(&nick_input).cloned();
+        match stored_pass {
+            Some(stored_pass) => {
+                let check = verify_blocking(stored_pass.clone(), pass_input.clone()).await?;
+                if check == PasswordCheck::Invalid {
+                    writer.send(&ServerEvent::error("wrong_password", "Неверный пароль. Попробуйте снова.")).await?;
+                    attempts -= 1;
+                    log_message(LogLevel::Info, "auth", &format!("Пользователь '{}' ввел неверный пароль. Осталось попыток: {}", nick_input, attempts));
+                    continue;
+                }
+                if check == PasswordCheck::ValidLegacy {
+                    if let Err(e) = upgrade_legacy_password(state, &nick_input, &stored_pass, pass_input).await {
+                        log_message(LogLevel::Error, "auth", &format!("Не удалось перехешировать пароль '{}': {}", nick_input, e));
+                    }
+                }
+                writer.send(&ServerEvent::AuthSuccess { nick: nick_input.clone(), registered: false }).await?;
+                log_message(LogLevel::Info, "auth", &format!("Пользователь '{}' авторизовался успешно.", nick_input));
                 return Ok(nick_input);
             }
             None => {
-                let mut writer_guard = writer.lock().await;
-                writer_guard.write_all("Пользователь не найден. Хотите зарегистрироваться? (да/нет):\n".as_bytes()).await?;
-                writer_guard.flush().await?;
-                let mut answer = String::new();
-                if reader.read_line(&mut answer).await? == 0 {
-                    log_message("Client", "Клиент отключился во время запроса регистрации.", Color::Yellow).await?;
+                writer.send(&ServerEvent::Prompt { field: PromptField::Register }).await?;
+                let Some(answer) = read_answer(reader, writer, shutdown).await? else {
+                    log_message(LogLevel::Info, "client", "Клиент отключился во время запроса регистрации.");
                     return Err("Клиент отключился во время регистрации".into());
-                }
-                let answer = answer.trim().to_lowercase();
+                };
+                let answer = answer.to_lowercase();
                 if answer == "да" || answer == "yes" {
-                    db_guard.insert(nick_input.clone(), pass_input.clone());
+                    let password_hash = hash_blocking(pass_input).await?;
+                    let mut db_guard = state.users_db.lock().await;
+                    if db_guard.contains_key(&nick_input) {
+                        drop(db_guard);
+                        writer.send(&ServerEvent::error("nick_taken", "Этот никнейм только что заняли. Попробуйте снова.")).await?;
+                        attempts -= 1;
+                        continue;
+                    }
+                    db_guard.insert(nick_input.clone(), password_hash.clone());
+                    // Файл меняется только под блокировкой базы, чтобы дозапись не потерялась при его перезаписи.
+                    add_user_to_file(&state.config().users_file, &nick_input, &password_hash).await?;
                     drop(db_guard);
-                    add_user_to_file("users.txt", &nick_input, &pass_input).await?;
-                    let mut writer_guard = writer.lock().await;
-                    writer_guard.write_all("Регистрация успешна! Вы авторизованы.\n".as_bytes()).await?;
-                    writer_guard.flush().await?;
-                    log_message("Auth", &format!("Пользователь '{}' зарегистрировался.", nick_input), Color::Green).await?;
+                    writer.send(&ServerEvent::AuthSuccess { nick: nick_input.clone(), registered: true }).await?;
+                    log_message(LogLevel::Info, "auth", &format!("Пользователь '{}' зарегистрировался.", nick_input));
                     return Ok(nick_input);
                 } else {
-                    writer_guard.write_all("Попробуйте снова.\n".as_bytes()).await?;
-                    writer_guard.flush().await?;
+                    writer.send(&ServerEvent::notice("auth_retry", "Попробуйте снова.")).await?;
                     attempts -= 1;
-                    log_message("Auth", &format!("Пользователь '{}' отклонил регистрацию. Осталось попыток: {}", nick_input, attempts), Color::Yellow).await?;
+                    log_message(LogLevel::Info, "auth", &format!("Пользователь '{}' отклонил регистрацию. Осталось попыток: {}", nick_input, attempts));
                 }
             }
-            Some(_) => {
-                let mut writer_guard = writer.lock().await;
-                writer_guard.write_all("Неверный пароль. Попробуйте снова.\n".as_bytes()).await?;
-                writer_guard.flush().await?;
-                attempts -= 1;
-                log_message("Auth", &format!("Пользователь '{}' ввел неверный пароль. Осталось попыток: {}", nick_input, attempts), Color::Yellow).await?;
-            }
         }
     }
 }
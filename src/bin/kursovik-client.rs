@@ -0,0 +1,503 @@
+use clap::Parser;
+use colored::Colorize;
+use kursovik::e2e::{KeyPair, SessionKey};
+use kursovik::event::{GroupInfo, PromptField, ServerEvent};
+use kursovik::groups::normalize_group_name;
+use kursovik::protocol::{ClientCommand, ClientStream, GroupKeyShare, JSON_MODE_SWITCH};
+use kursovik::tls::{load_connector, server_name};
+use rustyline::completion::Completer;
+use rustyline::config::{ColorMode, Configurer};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter};
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, ExternalPrinter, Helper};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Parser)]
+#[command(name = "kursovik-client", about = "Терминальный клиент для чат-сервера")]
+struct Args {
+    /// Адрес сервера
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    addr: String,
+    /// Подключаться по TLS, доверяя сертификату из этого файла (PEM)
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+    /// Имя сервера в сертификате
+    #[arg(long, default_value = "localhost")]
+    tls_name: String,
+}
+
+// Пока сервер ждёт пароль, строка на экране заменяется звёздочками. Приглашение может прийти,
+// когда строка уже редактируется, поэтому состояние проверяется при каждой перерисовке.
+struct PasswordMask {
+    view: Arc<Mutex<View>>,
+}
+
+impl PasswordMask {
+    fn masking(&self) -> bool {
+        self.view.lock().unwrap().prompt == Some(PromptField::Password)
+    }
+}
+
+impl Highlighter for PasswordMask {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if self.masking() {
+            Cow::Owned("*".repeat(line.chars().count()))
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    // Перерисовывать строку после каждого изменения, иначе введённый символ успеет показаться.
+    fn highlight_char(&self, _line: &str, _pos: usize, kind: CmdKind) -> bool {
+        kind != CmdKind::MoveCursor && self.masking()
+    }
+}
+
+impl Completer for PasswordMask {
+    type Candidate = String;
+}
+
+impl Hinter for PasswordMask {
+    type Hint = String;
+}
+
+impl Validator for PasswordMask {}
+
+impl Helper for PasswordMask {}
+
+// То, что клиент знает о своём состоянии по событиям от сервера.
+// Ключи личных чатов живут только здесь и никогда не покидают клиент.
+#[derive(Default)]
+struct View {
+    prompt: Option<PromptField>,
+    nick: Option<String>,
+    room: Option<String>,
+    // Ключевые пары, открытая часть которых ушла вместе с /pm, по нику собеседника.
+    outgoing_keys: HashMap<String, KeyPair>,
+    // Ники и открытые ключи тех, кто предложил личный чат, в порядке поступления.
+    incoming_requests: Vec<(String, Vec<u8>)>,
+    // Ключи установленных личных чатов по нику собеседника.
+    sessions: HashMap<String, SessionKey>,
+    // Собеседник, которому уходит ввод; None — текущая комната.
+    focus: Option<String>,
+    // Своя ключевая пара в каждой группе и текущий ключ группы с номером эпохи.
+    group_key_pairs: HashMap<String, KeyPair>,
+    group_keys: HashMap<String, (u64, SessionKey)>,
+    // Команды, которые клиент отправляет сам в ответ на события, например раздача ключа группы.
+    outbox: Vec<ClientCommand>,
+    closed: bool,
+}
+
+impl View {
+    fn prompt_text(&self) -> String {
+        match (self.prompt, &self.focus) {
+            (Some(PromptField::Nick), _) => "ник> ".to_string(),
+            (Some(PromptField::Password), _) => "пароль> ".to_string(),
+            (Some(PromptField::Register), _) => "да/нет> ".to_string(),
+            (None, Some(partner)) => format!("[ЛС {}]> ", partner),
+            (None, None) => match (&self.nick, &self.room) {
+                (Some(nick), Some(room)) => format!("{} {}> ", nick, room),
+                (Some(nick), None) => format!("{}> ", nick),
+                _ => "> ".to_string(),
+            },
+        }
+    }
+
+    fn forget(&mut self, partner: &str) {
+        self.sessions.remove(partner);
+        self.outgoing_keys.remove(partner);
+        self.incoming_requests.retain(|(from, _)| from != partner);
+    }
+
+    // Убирает из очереди запрос от nick или, если ник не указан, самый старый.
+    fn take_request(&mut self, nick: &str) -> Option<(String, Vec<u8>)> {
+        let index = if nick.is_empty() { 0 } else { self.incoming_requests.iter().position(|(from, _)| from == nick)? };
+        (index < self.incoming_requests.len()).then(|| self.incoming_requests.remove(index))
+    }
+
+    // Владелец создаёт ключ для новой эпохи и шифрует его для каждого участника.
+    fn issue_group_key(&mut self, group: &GroupInfo) -> Result<(), String> {
+        let key_pair = self.group_key_pairs.get(&group.name).ok_or("Нет своей ключевой пары для группы")?;
+        let group_key = SessionKey::generate();
+        let mut keys = Vec::new();
+        for member in group.members.iter().filter(|member| Some(&member.nick) != self.nick.as_ref()) {
+            let (nonce, ciphertext) = key_pair.derive_wrapping_key(&member.public_key)?.wrap(&group_key)?;
+            keys.push(GroupKeyShare { nick: member.nick.clone(), nonce, ciphertext });
+        }
+        if !keys.is_empty() {
+            self.outbox.push(ClientCommand::GroupKey { group: group.name.clone(), epoch: group.epoch, keys });
+        }
+        self.group_keys.insert(group.name.clone(), (group.epoch, group_key));
+        Ok(())
+    }
+
+    // Обновляет состояние и возвращает текст для вывода.
+    fn apply(&mut self, event: &ServerEvent) -> String {
+        match event {
+            ServerEvent::Prompt { field } => self.prompt = Some(*field),
+            ServerEvent::AuthSuccess { nick, .. } => self.nick = Some(nick.clone()),
+            ServerEvent::RoomJoined { room, .. } => self.room = Some(room.clone()),
+            ServerEvent::FocusChanged { partner } => self.focus = partner.clone(),
+            ServerEvent::PrivateChatRequest { from, public_key } => {
+                self.forget(from);
+                self.incoming_requests.push((from.clone(), public_key.clone()));
+            }
+            ServerEvent::PrivateChatAccepted { from, public_key } => {
+                let derived = match self.outgoing_keys.remove(from) {
+                    Some(key_pair) => key_pair.derive_session_key(public_key),
+                    None => Err("Нет ключевой пары для этого запроса".to_string()),
+                };
+                match derived {
+                    Ok(session_key) => {
+                        self.sessions.insert(from.clone(), session_key);
+                    }
+                    Err(e) => {
+                        return format!("{}{} {}\n", event.render_text(), "Не удалось согласовать ключ:".red(), e);
+                    }
+                }
+            }
+            ServerEvent::PrivateChatRejected { from }
+            | ServerEvent::PrivateChatBusy { from }
+            | ServerEvent::PrivateChatEnded { from }
+            | ServerEvent::PrivateChatCancelled { from }
+            | ServerEvent::PrivateChatExpired { partner: from } => {
+                self.forget(from);
+            }
+            ServerEvent::EncryptedPrivateMsg { from, nonce, ciphertext } => {
+                let Some(session_key) = self.sessions.get(from) else {
+                    return format!("{} {}\n", "Зашифрованное сообщение без ключа от".red(), from);
+                };
+                let marker = if self.focus.as_ref() == Some(from) { "" } else { " (/switch, чтобы ответить)" };
+                return match session_key.decrypt(nonce, ciphertext) {
+                    Ok(text) => format!("[ЛС от {}]{}: {}\n", from.cyan(), marker, text),
+                    Err(e) => format!("{} {}\n", "ОШИБКА:".red(), e),
+                };
+            }
+            ServerEvent::GroupUpdated { group } if self.nick.as_ref() == Some(&group.owner) => {
+                if let Err(e) = self.issue_group_key(group) {
+                    return format!("{}{} {}\n", event.render_text(), "Не удалось раздать ключ группы:".red(), e);
+                }
+            }
+            ServerEvent::GroupKey { group, from, public_key, epoch, nonce, ciphertext } => {
+                let received = match self.group_key_pairs.get(group) {
+                    Some(key_pair) => key_pair.derive_wrapping_key(public_key).and_then(|wrapping_key| wrapping_key.unwrap(nonce, ciphertext)),
+                    None => Err("Нет своей ключевой пары для группы".to_string()),
+                };
+                return match received {
+                    Ok(group_key) => {
+                        self.group_keys.insert(group.clone(), (*epoch, group_key));
+                        format!("Получен ключ группы {} (эпоха {}) от {}.\n", group.blue(), epoch, from)
+                    }
+                    Err(e) => format!("{} {}\n", "Не удалось получить ключ группы:".red(), e),
+                };
+            }
+            ServerEvent::EncryptedGroupMsg { group, from, epoch, nonce, ciphertext } => {
+                return match self.group_keys.get(group) {
+                    Some((key_epoch, group_key)) if key_epoch == epoch => match group_key.decrypt(nonce, ciphertext) {
+                        Ok(text) => format!("[{}] {}: {}\n", group.blue(), from.cyan(), text),
+                        Err(e) => format!("{} {}\n", "ОШИБКА:".red(), e),
+                    },
+                    _ => format!("{} {} от {}\n", "Нет ключа группы для сообщения в".red(), group, from),
+                };
+            }
+            // Фокус сбрасывается отдельным событием сразу после этого уведомления.
+            ServerEvent::SystemNotice { code, .. } if code == "private_chat_left" => {
+                if let Some(partner) = self.focus.clone() {
+                    self.forget(&partner);
+                }
+            }
+            _ => {}
+        }
+        event.render_text()
+    }
+}
+
+enum Input {
+    Send(ClientCommand),
+    Quit,
+    Local(String),
+}
+
+fn translate_input(line: &str, view: &mut View) -> Input {
+    if view.prompt.take().is_some() {
+        return Input::Send(ClientCommand::Answer { value: line.to_string() });
+    }
+
+    if let Some(command_line) = line.strip_prefix('/') {
+        let mut parts = command_line.splitn(2, ' ');
+        let command = parts.next().unwrap_or("").to_lowercase();
+        let args = parts.next().unwrap_or("").trim().to_string();
+        return match command.as_str() {
+            "quit" => Input::Quit,
+            "help" => Input::Send(ClientCommand::Help),
+            "list" => Input::Send(ClientCommand::List),
+            "pm" if args.is_empty() => Input::Local("Использование: /pm <ник>".to_string()),
+            "pm" => {
+                let key_pair = KeyPair::generate();
+                let public_key = key_pair.public_bytes();
+                view.outgoing_keys.insert(args.clone(), key_pair);
+                Input::Send(ClientCommand::Pm { nick: args, public_key })
+            }
+            "accept" => {
+                let key_pair = KeyPair::generate();
+                let Some((from, peer_public)) = view.take_request(&args) else {
+                    return Input::Local("Такого запроса на личный чат нет. Список запросов: /requests".to_string());
+                };
+                match key_pair.derive_session_key(&peer_public) {
+                    Ok(session_key) => {
+                        view.sessions.insert(from.clone(), session_key);
+                    }
+                    Err(e) => return Input::Local(format!("Не удалось согласовать ключ: {}", e)),
+                }
+                Input::Send(ClientCommand::Accept { nick: Some(from), public_key: key_pair.public_bytes() })
+            }
+            "reject" => match view.take_request(&args) {
+                Some((from, _)) => Input::Send(ClientCommand::Reject { nick: Some(from) }),
+                None => Input::Local("Такого запроса на личный чат нет. Список запросов: /requests".to_string()),
+            },
+            "requests" => Input::Send(ClientCommand::Requests),
+            "group" => translate_group_command(&args, view),
+            "g" => {
+                let (group, text) = args.split_once(' ').unwrap_or((&args, ""));
+                let Some(group) = normalize_group_name(group) else {
+                    return Input::Local("Использование: /g <группа> <текст>".to_string());
+                };
+                let Some((epoch, group_key)) = view.group_keys.get(&group) else {
+                    return Input::Local(format!("Ключа группы {} пока нет.", group));
+                };
+                match group_key.encrypt(text.trim()) {
+                    Ok((nonce, ciphertext)) => Input::Send(ClientCommand::GroupMsg { group, epoch: *epoch, nonce, ciphertext }),
+                    Err(e) => Input::Local(e),
+                }
+            }
+            "cancel" => {
+                // Без ника сервер отменит единственный запрос; ключ тогда заменится при следующем /pm.
+                view.outgoing_keys.remove(&args);
+                Input::Send(ClientCommand::Cancel { nick: Some(args).filter(|nick| !nick.is_empty()) })
+            }
+            "switch" => Input::Send(ClientCommand::Switch { nick: Some(args).filter(|nick| !nick.is_empty()) }),
+            "chats" => Input::Send(ClientCommand::Chats),
+            "join" => Input::Send(ClientCommand::Join { room: args }),
+            "leave" => Input::Send(ClientCommand::Leave),
+            "rooms" => Input::Send(ClientCommand::Rooms),
+            "history" if args.is_empty() => Input::Send(ClientCommand::History { limit: None }),
+            "history" => match args.parse() {
+                Ok(limit) => Input::Send(ClientCommand::History { limit: Some(limit) }),
+                Err(_) => Input::Local("Использование: /history [N]".to_string()),
+            },
+            "who" => Input::Send(ClientCommand::Who { room: Some(args).filter(|room| !room.is_empty()) }),
+            // Команды без ключей (/kick, /ban, /mute, /reload, /loglevel и т. п.) разбираются так же, как на сервере.
+            _ => match ClientCommand::parse_text(line) {
+                ClientCommand::Unknown(command) => Input::Local(format!("Неизвестная команда: '{}'. Введите /help (или /quit для выхода).", command)),
+                command => Input::Send(command),
+            },
+        };
+    }
+
+    if let Some(partner) = &view.focus {
+        if line.to_lowercase() == "выход" {
+            return Input::Send(ClientCommand::Exit);
+        }
+        let Some(session_key) = view.sessions.get(partner) else {
+            return Input::Local("Ключ личного чата не установлен. Введите 'выход'.".to_string());
+        };
+        return match session_key.encrypt(line) {
+            Ok((nonce, ciphertext)) => Input::Send(ClientCommand::Encrypted { to: partner.clone(), nonce, ciphertext }),
+            Err(e) => Input::Local(e),
+        };
+    }
+    match line.split_once(':') {
+        Some((to, text)) => Input::Send(ClientCommand::Dm { to: to.trim().to_string(), text: text.trim().to_string() }),
+        None => Input::Send(ClientCommand::Say { text: line.to_string() }),
+    }
+}
+
+// Ключевые пары групп создаются здесь, открытая часть уходит серверу вместе с командой.
+fn translate_group_command(args: &str, view: &mut View) -> Input {
+    let words: Vec<&str> = args.split_whitespace().collect();
+    let usage = "Использование: /group create|accept|decline|leave <группа>, /group invite <группа> <ник>, /group list";
+    let subcommand = words.first().map(|word| word.to_lowercase()).unwrap_or_default();
+    if subcommand == "list" {
+        return Input::Send(ClientCommand::GroupList);
+    }
+    let Some(group) = words.get(1).and_then(|group| normalize_group_name(group)) else {
+        return Input::Local(usage.to_string());
+    };
+    match subcommand.as_str() {
+        "create" | "accept" => {
+            let key_pair = KeyPair::generate();
+            let public_key = key_pair.public_bytes();
+            view.group_key_pairs.insert(group.clone(), key_pair);
+            if subcommand == "create" {
+                Input::Send(ClientCommand::GroupCreate { group, public_key })
+            } else {
+                Input::Send(ClientCommand::GroupAccept { group, public_key })
+            }
+        }
+        "invite" => Input::Send(ClientCommand::GroupInvite { group, nick: words.get(2).unwrap_or(&"").to_string() }),
+        "decline" => Input::Send(ClientCommand::GroupDecline { group }),
+        "leave" => {
+            view.group_key_pairs.remove(&group);
+            view.group_keys.remove(&group);
+            Input::Send(ClientCommand::GroupLeave { group })
+        }
+        _ => Input::Local(usage.to_string()),
+    }
+}
+
+// Вывод входящих сообщений поверх строки ввода; без терминала — обычный stdout.
+enum Output {
+    Printer(Box<dyn ExternalPrinter + Send>),
+    Stdout,
+}
+
+impl Output {
+    fn print(&mut self, text: String) {
+        match self {
+            Output::Printer(printer) => {
+                if printer.print(text.clone()).is_err() {
+                    print!("{}", text);
+                }
+            }
+            Output::Stdout => print!("{}", text),
+        }
+    }
+}
+
+async fn read_loop(reader: ReadHalf<ClientStream>, mut output: Output, view: Arc<Mutex<View>>, commands: mpsc::WeakUnboundedSender<String>) {
+    let mut lines = BufReader::new(reader).lines();
+    let mut json_mode = false;
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                output.print(format!("{} {}\n", "Ошибка чтения:".red(), e));
+                break;
+            }
+        };
+        // До подтверждения режима JSON сервер ещё пишет обычный текст — его пропускаем:
+        // приветствие сервер повторит событием после mode_changed.
+        let event: ServerEvent = match serde_json::from_str(&line) {
+            Ok(event) => event,
+            Err(_) if !json_mode => continue,
+            Err(e) => {
+                output.print(format!("{} {} ({})\n", "Непонятное сообщение от сервера:".red(), line, e));
+                continue;
+            }
+        };
+        if let ServerEvent::ModeChanged { .. } = event {
+            json_mode = true;
+            continue;
+        }
+        let (text, outbox) = {
+            let mut view_guard = view.lock().unwrap();
+            let text = view_guard.apply(&event);
+            (text, std::mem::take(&mut view_guard.outbox))
+        };
+        output.print(text);
+        // Слабая ссылка: после /quit write_loop должен завершиться, не дожидаясь read_loop.
+        for command in outbox {
+            if let (Some(commands), Ok(line)) = (commands.upgrade(), command.to_json_line()) {
+                let _ = commands.send(line);
+            }
+        }
+    }
+    view.lock().unwrap().closed = true;
+    output.print(format!("{}\n", "Соединение с сервером закрыто. Нажмите Enter для выхода.".yellow()));
+}
+
+async fn write_loop(mut writer: WriteHalf<ClientStream>, mut commands: mpsc::UnboundedReceiver<String>) {
+    if writer.write_all(format!("{}\n", JSON_MODE_SWITCH).as_bytes()).await.is_err() {
+        return;
+    }
+    while let Some(line) = commands.recv().await {
+        if writer.write_all(line.as_bytes()).await.is_err() || writer.flush().await.is_err() {
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+async fn connect(args: &Args) -> Result<ClientStream, Box<dyn Error + Send + Sync>> {
+    let socket = TcpStream::connect(&args.addr)
+        .await
+        .map_err(|e| format!("Не удалось подключиться к {}: {}", args.addr, e))?;
+    let Some(ca_path) = &args.tls_ca else {
+        return Ok(Box::new(socket));
+    };
+    let stream = load_connector(ca_path)?
+        .connect(server_name(&args.tls_name)?, socket)
+        .await
+        .map_err(|e| format!("Ошибка TLS-подключения к {}: {}", args.addr, e))?;
+    Ok(Box::new(stream))
+}
+
+fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let args = Args::parse();
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    let stream = runtime.block_on(connect(&args))?;
+    let (reader, writer) = tokio::io::split(stream);
+
+    let view = Arc::new(Mutex::new(View::default()));
+    let mut editor: Editor<PasswordMask, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(PasswordMask { view: view.clone() }));
+    // Маска выводится подсветкой, поэтому подсветка включена и там, где терминал не сообщает о поддержке цвета.
+    editor.set_color_mode(ColorMode::Forced);
+    let output = match editor.create_external_printer() {
+        Ok(printer) => Output::Printer(Box::new(printer)),
+        Err(_) => Output::Stdout,
+    };
+
+    let (command_tx, command_rx) = mpsc::unbounded_channel::<String>();
+    runtime.spawn(write_loop(writer, command_rx));
+    runtime.spawn(read_loop(reader, output, view.clone(), command_tx.downgrade()));
+
+    loop {
+        let prompt = view.lock().unwrap().prompt_text();
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let mut view_guard = view.lock().unwrap();
+        if view_guard.closed {
+            break;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let answering_password = view_guard.prompt == Some(PromptField::Password);
+        let input = translate_input(line, &mut view_guard);
+        drop(view_guard);
+
+        if !answering_password {
+            let _ = editor.add_history_entry(line);
+        }
+        match input {
+            Input::Send(command) => {
+                if command_tx.send(command.to_json_line()?).is_err() {
+                    break;
+                }
+            }
+            Input::Local(text) => println!("{}", text),
+            Input::Quit => break,
+        }
+    }
+
+    drop(command_tx);
+    runtime.shutdown_timeout(std::time::Duration::from_millis(500));
+    Ok(())
+}
@@ -1,87 +1,200 @@
-use tokio::net::TcpStream;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::sync::{Mutex, mpsc};
-use std::collections::HashMap;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::time::Instant;
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
 use std::error::Error;
-use crate::auth::authorize_user;
-use crate::message::{broadcast_message, send_to_user};
-use crate::log::log_message;
-use colored::Color;
-use aes_gcm::{Aes256Gcm, Nonce};
-use aes_gcm::aead::{Aead, KeyInit};
-use rand::{rngs::OsRng, RngCore};
-use hex;
-
-type Tx = mpsc::UnboundedSender<String>;
-
-#[derive(Debug, Clone)]
-pub enum ClientState {
-    PublicChat,
-    WaitingForPrivateChatResponse { target_nick: String, sent_key: Vec<u8> },
-    HasPendingPrivateChatRequest { from_nick: String, shared_key: Vec<u8> },
-    InPrivateChat { with_nick: String, shared_key: Vec<u8> },
+use std::time::Duration;
+use crate::auth::{authorize_user, ShuttingDown};
+use crate::e2e::{NONCE_LEN, PUBLIC_KEY_LEN};
+use crate::event::{GroupInfo, HelpEntry, PrivateChatInfo, PrivateChatStatus, ServerEvent};
+use crate::groups::{normalize_group_name, GroupError};
+use crate::history::MAX_HISTORY_REPLAY;
+use crate::message::{broadcast_to_room, disconnect_user, send_to_user, send_to_users};
+use crate::moderation::format_time;
+use crate::outbound::{client_queue, WeakClientSender};
+use crate::log::{log_enabled, log_event, log_message, log_user_message, set_log_nick, LogLevel};
+use crate::protocol::{ClientCommand, ClientStream, ClientWriter, GroupKeyShare};
+use crate::ratelimit::{Action, RateLimiter, Verdict};
+use crate::rooms::normalize_room_name;
+use crate::server::{ConnectedUsers, ServerState};
+
+// Таблица личных чатов подключения и текущий фокус ввода.
+#[derive(Debug, Clone, Default)]
+pub struct ClientState {
+    pub private_chats: BTreeMap<String, PrivateChatStatus>,
+    // Собеседник, которому уходит обычный ввод; None — текущая комната.
+    pub focus: Option<String>,
+    // Когда истекают исходящие запросы. Срок назначается один раз при отправке запроса, и истечение
+    // решает только отправитель: получатель узнаёт о нём событием, а не по своему таймеру.
+    pub request_deadlines: BTreeMap<String, Instant>,
+    // Входящие запросы в порядке поступления.
+    pub incoming_requests: VecDeque<String>,
+}
+
+impl ClientState {
+    pub fn is_active(&self, nick: &str) -> bool {
+        self.private_chats.get(nick) == Some(&PrivateChatStatus::Active)
+    }
+
+    // Входящий запрос от nick, а без ника — самый старый.
+    pub fn pending_request(&self, nick: Option<&str>) -> Option<String> {
+        match nick {
+            Some(nick) => self.incoming_requests.iter().find(|partner| *partner == nick).cloned(),
+            None => self.incoming_requests.front().cloned(),
+        }
+    }
+
+    pub fn outgoing_requests(&self) -> Vec<String> {
+        self.private_chats
+            .iter()
+            .filter(|(_, status)| **status == PrivateChatStatus::WaitingForResponse)
+            .map(|(nick, _)| nick.clone())
+            .collect()
+    }
+
+    // Убирает собеседника из всех таблиц и возвращает его прежний статус.
+    pub fn remove(&mut self, nick: &str) -> Option<PrivateChatStatus> {
+        self.request_deadlines.remove(nick);
+        self.incoming_requests.retain(|partner| partner != nick);
+        self.private_chats.remove(nick)
+    }
+}
+
+// Всё, что нужно задачам чтения и записи одного подключения.
+#[derive(Clone)]
+struct Session {
+    nickname: String,
+    server: Arc<ServerState>,
+    writer: ClientWriter,
+    client_state: Arc<Mutex<ClientState>>,
+    // Своя очередь событий: по ней сессия узнаёт свою запись в connected_users.
+    events: WeakClientSender,
+    limiter: Arc<Mutex<RateLimiter>>,
+    // Будит задачу сроков запросов, когда появляется новый срок.
+    request_timer: Arc<Notify>,
+}
+
+const DEFAULT_HISTORY_REPLAY: usize = 20;
+// Сверх этого числа необработанных входящих запросов отправители получают отказ "занят".
+const MAX_INCOMING_REQUESTS: usize = 10;
+
+const ADMIN_HELP: &[(&str, &str)] = &[
+    ("/kick <ник> [причина]", "(администратор) Отключить пользователя"),
+    ("/ban <ник> [причина]", "(администратор) Заблокировать пользователя и отключить его"),
+    ("/unban <ник>", "(администратор) Снять блокировку"),
+    ("/mute <ник> <время>", "(администратор) Запретить писать в комнаты, например /mute bob 15m (s, m, h, d)"),
+    ("/unmute <ник>", "(администратор) Снова разрешить писать в комнаты"),
+    ("/reload", "(администратор) Перечитать файл пользователей и конфигурацию"),
+    ("/loglevel [уровень]", "(администратор) Показать или сменить уровень логирования: error, warn, info, debug, trace"),
+];
+
+fn help_entries(is_admin: bool) -> Vec<HelpEntry> {
+    let admin_entries = if is_admin { ADMIN_HELP } else { &[] };
+    [
+        ("/help", "Показать это сообщение"),
+        ("/list", "Показать список подключённых пользователей"),
+        ("/join <комната>", "Перейти в комнату, например /join #dev"),
+        ("/leave", "Вернуться в комнату по умолчанию"),
+        ("/rooms", "Показать список комнат"),
+        ("/who [комната]", "Показать участников комнаты (по умолчанию текущей)"),
+        ("/history [N]", "Показать последние N сообщений текущей комнаты"),
+        ("/pm <ник> <ключ>", "Предложить личный чат пользователю <ник>, передав открытый ключ X25519 (hex)"),
+        ("/requests", "Показать входящие запросы на личный чат"),
+        ("/accept [ник] <ключ>", "Принять запрос от <ник> (по умолчанию самый старый), передав свой открытый ключ X25519 (hex)"),
+        ("/reject [ник]", "Отклонить запрос от <ник> (по умолчанию самый старый)"),
+        ("/cancel [ник]", "Отменить свой запрос на личный чат"),
+        ("/chats", "Показать ваши личные чаты"),
+        ("/switch [ник]", "Перевести ввод в личный чат с <ник> или, без ника, обратно в комнату"),
+        ("/group create <группа> <ключ>", "Создать закрытую группу, передав свой открытый ключ X25519 (hex)"),
+        ("/group invite <группа> <ник>", "(владелец) Пригласить пользователя в группу"),
+        ("/group accept <группа> <ключ>", "Принять приглашение в группу, передав свой открытый ключ X25519 (hex)"),
+        ("/group decline <группа>", "Отклонить приглашение в группу"),
+        ("/group leave <группа>", "Выйти из группы"),
+        ("/group list", "Показать ваши группы"),
+        ("/group key <группа> <эпоха> <ник> <nonce> <шифртекст>", "(владелец) Передать участнику ключ группы (hex)"),
+        ("/group send <группа> <эпоха> <nonce> <шифртекст>", "Отправить зашифрованное сообщение в группу (hex)"),
+        ("<ник>: <текст>", "Отправить сообщение одному пользователю"),
+        ("/encrypted <ник> <nonce> <шифртекст>", "(в приватном чате) Отправить зашифрованное сообщение (hex)"),
+        ("'выход'", "(в приватном чате) Закончить личный чат, который сейчас в фокусе"),
+        ("любое_сообщение", "Отправить сообщение всем в текущей комнате"),
+    ]
+    .iter()
+    .chain(admin_entries)
+    .map(|(command, description)| HelpEntry { command: command.to_string(), description: description.to_string() })
+    .collect()
+}
+
+// Новый состав группы получают все её участники, включая того, кто его изменил.
+async fn announce_group(connected_users: &ConnectedUsers, info: GroupInfo) {
+    let members: Vec<String> = info.members.iter().map(|member| member.nick.clone()).collect();
+    send_to_users(connected_users, &members, ServerEvent::GroupUpdated { group: info }).await;
 }
 
 pub async fn handle_client(
-    socket: TcpStream,
-    users_db: Arc<Mutex<HashMap<String, String>>>,
-    connected_users: Arc<Mutex<HashMap<String, Tx>>>,
+    socket: ClientStream,
+    state: Arc<ServerState>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let (reader_half, writer_half) = socket.into_split();
+    let connected_users = state.connected_users.clone();
+    let (reader_half, writer_half) = tokio::io::split(socket);
     let mut reader = BufReader::new(reader_half);
-    let writer_arc = Arc::new(Mutex::new(writer_half));
+    let writer = ClientWriter::new(writer_half);
 
-    {
-        let mut writer_guard = writer_arc.lock().await;
-        writer_guard.write_all("Добро пожаловать в чат! Введите /help для списка команд.\n".as_bytes()).await?;
-        writer_guard.flush().await?;
-    }
+    writer.send(&ServerEvent::Welcome { text: state.config().welcome.clone() }).await?;
 
-    let nickname = authorize_user(&mut reader, &writer_arc, users_db).await?;
+    let nickname = match authorize_user(&mut reader, &writer, &state, &mut shutdown).await {
+        Ok(nickname) => nickname,
+        Err(e) if e.is::<ShuttingDown>() => {
+            log_message(LogLevel::Info, "client", "Сервер остановлен до завершения авторизации клиента.");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    set_log_nick(&nickname);
 
-    {
-        let users_guard = connected_users.lock().await;
+    let (tx_to_client, rx_from_others) = client_queue(&nickname, &state.config().outbound, state.outbound_stats.clone());
+    let events = tx_to_client.downgrade();
+    let connected_list = {
+        let mut users_guard = connected_users.lock().await;
         if users_guard.contains_key(&nickname) {
-            let mut writer_guard = writer_arc.lock().await;
-            writer_guard.write_all("Пользователь с таким ником уже в сети. Отключение.\n".as_bytes()).await?;
-            writer_guard.flush().await?;
-            log_message("Auth", &format!("Обнаружен дубликат никнейма '{}'. Отключение клиента.", nickname), Color::Red).await?;
+            drop(users_guard);
+            writer.send(&ServerEvent::error("nick_in_use", "Пользователь с таким ником уже в сети. Отключение.")).await?;
+            log_message(LogLevel::Warn, "auth", &format!("Обнаружен дубликат никнейма '{}'. Отключение клиента.", nickname));
             return Err("Дубликат никнейма".into());
         }
-    }
-
-    {
-        let users_guard = connected_users.lock().await;
-        let connected_list: Vec<String> = users_guard.keys()
-            .filter(|name| *name != &nickname)
-            .cloned()
-            .collect();
-        let mut writer_guard = writer_arc.lock().await;
-        if connected_list.is_empty() {
-            writer_guard.write_all("Пока никто больше не подключён.\n".as_bytes()).await?;
-        } else {
-            writer_guard.write_all(format!("Сейчас в сети: {}\n", connected_list.join(", ")).as_bytes()).await?;
+        // Флаг ставится до рассылки уведомления об остановке под этой же блокировкой,
+        // поэтому сессия, вошедшая позже рассылки, его увидит.
+        if state.is_shutting_down() {
+            drop(users_guard);
+            writer.send(&ServerEvent::server_shutdown()).await?;
+            return Ok(());
         }
-        writer_guard.flush().await?;
-    }
-
-    let (tx_to_client, rx_from_others) = mpsc::unbounded_channel::<String>();
-    {
-        let mut users_guard = connected_users.lock().await;
+        let mut connected_list: Vec<String> = users_guard.keys().cloned().collect();
+        connected_list.sort();
         users_guard.insert(nickname.clone(), tx_to_client);
-    }
+        connected_list
+    };
+    writer.send(&ServerEvent::UserList { users: connected_list }).await?;
 
-    let join_msg = format!("Пользователь '{}' вошёл в чат", nickname);
-    log_message("Auth", &join_msg, Color::Yellow).await?;
-    broadcast_message(&connected_users, &nickname, &join_msg, true).await;
-    let client_state = Arc::new(Mutex::new(ClientState::PublicChat));
+    log_message(LogLevel::Info, "auth", &format!("Пользователь '{}' вошёл в чат", nickname));
 
-    let read_task = tokio::spawn({
-        let writer_arc_clone = writer_arc.clone();
-        let connected_users_read = connected_users.clone();
-        let nickname_read = nickname.clone();
-        let client_state_read = client_state.clone();
+    let session = Session {
+        nickname: nickname.clone(),
+        server: state.clone(),
+        writer,
+        client_state: Arc::new(Mutex::new(ClientState::default())),
+        events,
+        limiter: Arc::new(Mutex::new(RateLimiter::new(&state.config().rate_limit))),
+        request_timer: Arc::new(Notify::new()),
+    };
+    session.deliver_offline_messages().await?;
+    let default_room = state.rooms.lock().await.default_room().to_string();
+    session.move_to_room(&default_room).await?;
+
+    let slow_disconnect = rx_from_others.slow_disconnect();
+    let read_task = {
+        let session = session.clone();
         let mut reader = reader;
 
         async move {
@@ -89,12 +202,12 @@ pub async fn handle_client(
                 let mut line = String::new();
                 let _bytes_read = match reader.read_line(&mut line).await {
                     Ok(0) => {
-                        log_message("Client", &format!("{}: Клиент отключился (прочитано 0 байт).", nickname_read), Color::Cyan).await?;
+                        log_message(LogLevel::Info, "client", &format!("{}: Клиент отключился (прочитано 0 байт).", session.nickname));
                         break Ok(());
                     },
                     Ok(n) => n,
                     Err(e) => {
-                        log_message("Error", &format!("Ошибка чтения от {}: {}", nickname_read, e), Color::Red).await?;
+                        log_message(LogLevel::Error, "client", &format!("Ошибка чтения от {}: {}", session.nickname, e));
                         break Err(e.into());
                     },
                 };
@@ -102,578 +215,1122 @@ pub async fn handle_client(
                 let msg_trimmed = line.trim();
                 if msg_trimmed.is_empty() { continue; }
 
-                if msg_trimmed.to_lowercase() == "выход" {
-                    let mut state_guard = client_state_read.lock().await;
-                    if let ClientState::InPrivateChat { with_nick, shared_key: _ } = &*state_guard {
-                        let partner_nick = with_nick.clone();
-                        *state_guard = ClientState::PublicChat;
-                        drop(state_guard);
-
-                        let _ = send_to_user(
-                            &connected_users_read,
-                            &partner_nick,
-                            format!("SYSTEM:PRIVATE_CHAT_ENDED:{}", nickname_read)
-                        ).await;
-
-                        let mut writer_guard = writer_arc_clone.lock().await;
-                        writer_guard.write_all("Вы вышли из личного чата. Возвращение в общий чат.\n".as_bytes()).await?;
-                        writer_guard.flush().await?;
-                        drop(writer_guard);
-
-                        log_message("Info", &format!("'{}' вышел из приватного чата с '{}'", nickname_read, partner_nick), Color::Cyan).await?;
+                let command = match session.writer.parse_command(msg_trimmed) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        session.writer.send(&ServerEvent::error("bad_request", format!("Некорректная JSON-команда: {}", e))).await?;
+                        log_message(LogLevel::Debug, "command", &format!("'{}' прислал некорректную JSON-команду: {}", session.nickname, e));
                         continue;
                     }
-                    drop(state_guard);
-                }
-
-                if msg_trimmed.starts_with('/') {
-                    let mut parts = msg_trimmed[1..].splitn(2, ' ');
-                    let command = parts.next().unwrap_or("").to_lowercase();
-                    let args = parts.next().unwrap_or("").trim();
-
-                    match command.as_str() {
-                        "help" => {
-                            let mut writer_guard = writer_arc_clone.lock().await;
-                            writer_guard.write_all(
-                                "Доступные команды:\n\
-                                 \t/help - Показать это сообщение\n\
-                                 \t/list - Показать список подключённых пользователей\n\
-                                 \t/pm <ник> - Предложить личный чат пользователю <ник>\n\
-                                 \t/accept - Принять запрос на личный чат\n\
-                                 \t/reject - Отклонить запрос на личный чат\n\
-                                 \t'выход' - (в приватном чате) Выйти из приватного чата\n\
-                                 \tлюбое_сообщение - Отправить сообщение всем в публичный чат\n"
-                                .as_bytes()
-                            ).await?;
-                            writer_guard.flush().await?;
-                            drop(writer_guard);
-                            log_message("Cmd", &format!("'{}' запросил /help", nickname_read), Color::Magenta).await?;
-                        }
-                        "list" => {
-                            let users = connected_users_read.lock().await;
-                            let connected_list: Vec<String> = users.keys()
-                                .filter(|name| *name != &nickname_read)
-                                .cloned()
-                                .collect();
-                            drop(users);
-
-                            let mut writer_guard = writer_arc_clone.lock().await;
-                            if connected_list.is_empty() {
-                                writer_guard.write_all("Пока никто больше не подключён.\n".as_bytes()).await?;
-                            } else {
-                                writer_guard.write_all(format!("Сейчас в сети: {}\n", connected_list.join(", ")).as_bytes()).await?;
-                            }
-                            writer_guard.flush().await?;
-                            drop(writer_guard);
-                            log_message("Cmd", &format!("'{}' запросил /list. Онлайн пользователи: {}", nickname_read, connected_list.join(", ")), Color::Magenta).await?;
-                        }
-                        "pm" => {
-                            if args.is_empty() {
-                                let mut writer_guard = writer_arc_clone.lock().await;
-                                writer_guard.write_all("Укажите ник пользователя для личного чата: /pm <ник>\n".as_bytes()).await?;
-                                writer_guard.flush().await?;
-                                drop(writer_guard);
-                                log_message("Cmd", &format!("'{}' ввел /pm без цели.", nickname_read), Color::Red).await?;
-                            } else if args == nickname_read {
-                                let mut writer_guard = writer_arc_clone.lock().await;
-                                writer_guard.write_all("Вы не можете начать личный чат с самим собой.\n".as_bytes()).await?;
-                                writer_guard.flush().await?;
-                                drop(writer_guard);
-                                log_message("Cmd", &format!("'{}' пытался начать /pm с самим собой.", nickname_read), Color::Red).await?;
-                            }
-                            else {
-                                let mut state_guard = client_state_read.lock().await;
-                                match &mut *state_guard {
-                                    ClientState::PublicChat => {
-                                        let target_nick = args.to_string();
-                                        let mut key_bytes = [0u8; 32];
-                                        OsRng.fill_bytes(&mut key_bytes);
-                                        let shared_key = key_bytes.to_vec();
-                                        let key_hex = hex::encode(&shared_key);
-
-                                        let current_nickname = nickname_read.clone();
-                                        let target_nick_clone = target_nick.clone();
-                                        let shared_key_clone = shared_key.clone();
-                                        *state_guard = ClientState::WaitingForPrivateChatResponse { target_nick: target_nick.clone(), sent_key: shared_key_clone };
-                                        drop(state_guard);
-
-                                        if send_to_user(&connected_users_read, &target_nick_clone, format!("SYSTEM:PRIVATE_CHAT_REQUEST:{}:{}", current_nickname, key_hex)).await.is_ok() {
-                                            let mut writer_guard = writer_arc_clone.lock().await;
-                                            writer_guard.write_all(format!("Запрос на личный чат отправлен пользователю '{}'. Ожидание ответа...\n", target_nick_clone).as_bytes()).await?;
-                                            writer_guard.flush().await?;
-                                            drop(writer_guard);
-                                            log_message("Private chat", &format!("'{}' запросил приватный чат у '{}'", current_nickname, target_nick_clone), Color::Cyan).await?;
-                                        } else {
-                                            let mut writer_guard = writer_arc_clone.lock().await;
-                                            writer_guard.write_all(format!("Пользователь '{}' не найден или не в сети.\n", target_nick_clone).as_bytes()).await?;
-                                            writer_guard.flush().await?;
-                                            drop(writer_guard);
-                                            log_message("Private chat", &format!("'{}' пытался запросить приватный чат у оффлайн пользователя '{}'", current_nickname, target_nick_clone), Color::Red).await?;
-                                        }
-                                    }
-                                    _ => {
-                                        let state_for_log = format!("{:?}", *state_guard);
-                                        drop(state_guard);
-                                        let mut writer_guard = writer_arc_clone.lock().await;
-                                        writer_guard.write_all("Вы не можете начать новый личный чат, находясь не в общем чате.\n".as_bytes()).await?;
-                                        writer_guard.flush().await?;
-                                        drop(writer_guard);
-                                        log_message("Private chat", &format!("'{}' пытался инициировать ЛС, находясь не в общем чате (текущее состояние: {})", nickname_read, state_for_log), Color::Red).await?;
-                                    }
-                                }
-                            }
-                        }
-                        "accept" => {
-                            let mut state_guard = client_state_read.lock().await;
-                            if let ClientState::HasPendingPrivateChatRequest { from_nick, shared_key } = &mut *state_guard {
-                                let partner_nick = from_nick.clone();
-                                let key_to_use = shared_key.clone();
-                                let current_nickname = nickname_read.clone();
-                                *state_guard = ClientState::InPrivateChat { with_nick: partner_nick.clone(), shared_key: key_to_use };
-                                drop(state_guard);
-
-                                if send_to_user(&connected_users_read, &partner_nick, format!("SYSTEM:PRIVATE_CHAT_ACCEPTED:{}", current_nickname)).await.is_ok() {
-                                    let mut writer_guard = writer_arc_clone.lock().await;
-                                    writer_guard.write_all(format!("Вы начали личный чат с '{}'. Напишите 'выход' для возврата в общий чат.\n", partner_nick).as_bytes()).await?;
-                                    writer_guard.flush().await?;
-                                    drop(writer_guard);
-                                    log_message("Private chat", &format!("'{}' обновил статус: приватный чат с '{}'", current_nickname, partner_nick), Color::Cyan).await?;
-                                } else {
-                                    let mut writer_guard = writer_arc_clone.lock().await;
-                                    writer_guard.write_all(format!("Не удалось уведомить '{}', возможно, он отключился. Вы возвращены в общий чат.\n", partner_nick).as_bytes()).await?;
-                                    writer_guard.flush().await?;
-                                    drop(writer_guard);
-                                    log_message("Private chat", &format!("'{}' принял приватный чат от '{}', но не смог уведомить партнера.", current_nickname, partner_nick), Color::Red).await?;
-                                    let mut state_guard_revert = client_state_read.lock().await;
-                                    *state_guard_revert = ClientState::PublicChat;
-                                    drop(state_guard_revert);
-                                }
-                            } else {
-                                drop(state_guard);
-                                let mut writer_guard = writer_arc_clone.lock().await;
-                                writer_guard.write_all("Нет активных запросов на личный чат для принятия.\n".as_bytes()).await?;
-                                writer_guard.flush().await?;
-                                drop(writer_guard);
-                                log_message("Cmd", &format!("'{}' пытался /accept без ожидающего запроса.", nickname_read), Color::Yellow).await?;
-                            }
-                        }
-                        "reject" => {
-                            let mut state_guard = client_state_read.lock().await;
-                            if let ClientState::HasPendingPrivateChatRequest { from_nick, shared_key: _ } = &mut *state_guard {
-                                let partner_nick = from_nick.clone();
-                                let current_nickname = nickname_read.clone();
-                                *state_guard = ClientState::PublicChat;
-                                drop(state_guard);
-
-                                if send_to_user(&connected_users_read, &partner_nick, format!("SYSTEM:PRIVATE_CHAT_REJECTED:{}", current_nickname)).await.is_ok() {
-                                    let mut writer_guard = writer_arc_clone.lock().await;
-                                    writer_guard.write_all(format!("Вы отклонили запрос на личный чат от '{}'.\n", partner_nick).as_bytes()).await?;
-                                    writer_guard.flush().await?;
-                                    drop(writer_guard);
-                                    log_message("Private chat", &format!("'{}' отклонил приватный чат от '{}'", current_nickname, partner_nick), Color::Cyan).await?;
-                                } else {
-                                    let mut writer_guard = writer_arc_clone.lock().await;
-                                    writer_guard.write_all(format!("Не удалось уведомить '{}' об отклонении, возможно, он отключился.\n", partner_nick).as_bytes()).await?;
-                                    writer_guard.flush().await?;
-                                    drop(writer_guard);
-                                    log_message("Private chat", &format!("'{}' отклонил приватный чат от '{}', но не смог уведомить партнера.", current_nickname, partner_nick), Color::Red).await?;
-                                }
-                            } else {
-                                drop(state_guard);
-                                let mut writer_guard = writer_arc_clone.lock().await;
-                                writer_guard.write_all("Нет активных запросов на личный чат для отклонения.\n".as_bytes()).await?;
-                                writer_guard.flush().await?;
-                                drop(writer_guard);
-                                log_message("Cmd", &format!("'{}' пытался /reject без ожидающего запроса.", nickname_read), Color::Yellow).await?;
-                            }
-                        }
-                        _ => {
-                            let mut writer_guard = writer_arc_clone.lock().await;
-                            writer_guard.write_all(format!("Неизвестная команда: '{}'. Введите /help.\n", command).as_bytes()).await?;
-                            writer_guard.flush().await?;
-                            drop(writer_guard);
-                            log_message("Cmd", &format!("'{}' ввел неизвестную команду: '{}'", nickname_read, command), Color::Red).await?;
-                        }
-                    }
-                }
-                else {
-                    let current_state_clone;
-                    {
-                        let state_guard = client_state_read.lock().await;
-                        current_state_clone = state_guard.clone();
-                    }
-
-                    match current_state_clone {
-                        ClientState::InPrivateChat { with_nick, shared_key } => {
-                            let cipher = Aes256Gcm::new_from_slice(&shared_key).expect("Key length is 32 bytes");
-                            let mut nonce_array = [0u8; 12];
-                            OsRng.fill_bytes(&mut nonce_array);
-                            let nonce = Nonce::from_slice(&nonce_array);
-
-                            let ciphertext_result = cipher.encrypt(&nonce, msg_trimmed.as_bytes());
-                            match ciphertext_result {
-                                Ok(ciphertext) => {
-                                    let encrypted_msg = format!(
-                                        "SYSTEM:ENCRYPTED_PRIVATE_MSG:{}:{}:{}",
-                                        nickname_read,
-                                        hex::encode(nonce_array),
-                                        hex::encode(ciphertext)
-                                    );
-                                    if send_to_user(&connected_users_read, &with_nick, encrypted_msg).await.is_ok() {
-                                        log_message("Private", &format!("'{}' отправил зашифрованное ЛС '{}'", nickname_read, with_nick), Color::Blue).await?;
-                                    } else {
-                                        let mut writer_guard = writer_arc_clone.lock().await;
-                                        writer_guard.write_all(format!("Не удалось отправить сообщение '{}'. Возможно, пользователь отключился. Вы возвращены в общий чат.\n", with_nick).as_bytes()).await?;
-                                        writer_guard.flush().await?;
-                                        drop(writer_guard);
-                                        log_message("Private", &format!("'{}' не смог отправить зашифрованное ЛС '{}'. Партнер отключился.", nickname_read, with_nick), Color::Red).await?;
-                                        let mut state_guard_revert = client_state_read.lock().await;
-                                        *state_guard_revert = ClientState::PublicChat;
-                                        drop(state_guard_revert);
-                                    }
-                                }
-                                Err(e) => {
-                                    log_message("Error", &format!("Ошибка шифрования для {}: {:?}", nickname_read, e), Color::Red).await?;
-                                    let mut writer_guard = writer_arc_clone.lock().await;
-                                    writer_guard.write_all("Ошибка шифрования сообщения. Попробуйте снова.\n".as_bytes()).await?;
-                                    writer_guard.flush().await?;
-                                    drop(writer_guard);
-                                }
-                            }
-                        }
-                        ClientState::PublicChat => {
-                            if let Some(idx) = msg_trimmed.find(':') {
-                                let recipient = msg_trimmed[..idx].trim().to_string();
-                                let message_content = msg_trimmed[idx + 1..].trim().to_string();
-
-                                if recipient == nickname_read {
-                                     let mut writer_guard = writer_arc_clone.lock().await;
-                                     writer_guard.write_all("Вы не можете отправить ЛС самому себе.\n".as_bytes()).await?;
-                                     writer_guard.flush().await?;
-                                     drop(writer_guard);
-                                     log_message("Message", &format!("'{}' пытался отправить ЛС самому себе.", nickname_read), Color::Red).await?;
-                                } else {
-                                    let full_msg = format!("{} {}: {}\n", colored::Colorize::cyan("Вам"), nickname_read, message_content);
-                                    if send_to_user(&connected_users_read, &recipient, full_msg).await.is_ok() {
-                                        log_message("Message", &format!("'{}' отправил прямое сообщение '{}'", nickname_read, recipient), Color::Green).await?;
-                                    } else {
-                                        let mut writer_guard = writer_arc_clone.lock().await;
-                                        writer_guard.write_all(format!("{} Пользователь '{}' не найден или не в сети.\n", colored::Colorize::red("Ошибка:"), recipient).as_bytes()).await?;
-                                        writer_guard.flush().await?;
-                                        drop(writer_guard);
-                                        log_message("Message", &format!("'{}' не смог отправить прямое сообщение оффлайн пользователю '{}'", nickname_read, recipient), Color::Red).await?;
-                                    }
-                                }
-                            } else {
-                                broadcast_message(&connected_users_read, &nickname_read, msg_trimmed, false).await;
-                            }
-                        }
-                        ClientState::WaitingForPrivateChatResponse { target_nick, sent_key: _ } => {
-                            let mut writer_guard = writer_arc_clone.lock().await;
-                            writer_guard.write_all(format!("Вы ожидаете ответа от '{}'. Чтобы отправить сообщение в общий чат, сначала отмените запрос (пока не реализовано) или дождитесь ответа.\n", target_nick).as_bytes()).await?;
-                            writer_guard.flush().await?;
-                            drop(writer_guard);
-                            log_message("Client state", &format!("'{}' пытался отправить сообщение в состоянии WaitingForPrivateChatResponse.", nickname_read), Color::Yellow).await?;
-                        }
-                        ClientState::HasPendingPrivateChatRequest { from_nick, shared_key: _ } => {
-                            let mut writer_guard = writer_arc_clone.lock().await;
-                            writer_guard.write_all(format!("У вас есть запрос на личный чат от '{}'. Введите /accept или /reject.\n", from_nick).as_bytes()).await?;
-                            writer_guard.flush().await?;
-                            drop(writer_guard);
-                            log_message("Client state", &format!("'{}' пытался отправить сообщение в состоянии HasPendingPrivateChatRequest.", nickname_read), Color::Yellow).await?;
-                        }
-                    }
-                }
+                };
+                session.handle_command(command).await?;
             };
             res
         }
-    });
+    };
 
-    let write_task = tokio::spawn({
-        let writer_arc_for_task = writer_arc.clone();
-        let client_state_write = client_state.clone();
-        let connected_users_write = connected_users.clone();
-        let nickname_write = nickname.clone();
+    let write_task = {
+        let session = session.clone();
         let mut rx_from_others = rx_from_others;
 
         async move {
             let res: Result<(), Box<dyn Error + Send + Sync>> = loop {
-                let msg_str = match rx_from_others.recv().await {
-                    Some(msg) => {
-                        log_message("Recieve", &format!("Получено write_task ({}): {}", nickname_write, msg.trim()), Color::Yellow).await?;
-                        msg
+                let event = match rx_from_others.recv().await {
+                    Some(event) => {
+                        if log_enabled(LogLevel::Trace) {
+                            log_event(LogLevel::Trace, "client", &format!("Получено write_task ({})", session.nickname), &event, &[]);
+                        }
+                        event
                     },
                     None => {
-                        log_message("Client", &format!("{}: Канал rx_from_others закрыт (write_task завершается).", nickname_write), Color::Cyan).await?;
+                        log_message(LogLevel::Info, "client", &format!("{}: Канал rx_from_others закрыт (write_task завершается).", session.nickname));
                         break Ok(());
                     },
                 };
-
-                if msg_str.starts_with("SYSTEM:") {
-                    let parts: Vec<&str> = msg_str.splitn(2, ':').collect();
-                    if parts.len() < 2 {
-                        log_message("Error", &format!("Некорректное системное сообщение: {}", msg_str), Color::Red).await?;
-                        continue;
-                    }
-
-                    let command_and_args = parts[1];
-                    let mut command_parts = command_and_args.splitn(2, ':');
-                    let command = command_parts.next().unwrap_or("");
-                    let args = command_parts.next().unwrap_or("");
-
-                    match command {
-                        "PRIVATE_CHAT_REQUEST" => {
-                            let request_args: Vec<&str> = args.splitn(2, ':').collect();
-                            if request_args.len() == 2 {
-                                let sender_nick = request_args[0].to_string();
-                                let key_hex = request_args[1];
-                                match hex::decode(key_hex) {
-                                    Ok(shared_key) => {
-                                        let mut state_guard = client_state_write.lock().await;
-                                        match &mut *state_guard {
-                                            ClientState::PublicChat => {
-                                                *state_guard = ClientState::HasPendingPrivateChatRequest { from_nick: sender_nick.clone(), shared_key };
-                                                drop(state_guard);
-                                                let mut writer_guard = writer_arc_for_task.lock().await;
-                                                if writer_guard.write_all(format!("Пользователь '{}' хочет начать с вами личный чат. Введите /accept или /reject.\n", sender_nick).as_bytes()).await.is_err() { break Ok(()); }
-                                                writer_guard.flush().await?;
-                                                drop(writer_guard);
-                                                log_message("Private chat", &format!("'{}' получил запрос на приватный чат от '{}'", nickname_write, sender_nick), Color::Cyan).await?;
-                                            }
-                                            _ => {
-                                                drop(state_guard);
-                                                let _ = send_to_user(&connected_users_write, &sender_nick, format!("SYSTEM:PRIVATE_CHAT_BUSY:{}", nickname_write)).await;
-                                                log_message("Private chat", &format!("'{}' получил запрос на приватный чат от '{}', но был занят.", nickname_write, sender_nick), Color::Yellow).await?;
-                                            }
-                                        }
-                                    }
-                                    Err(_) => {
-                                        let mut writer_guard = writer_arc_for_task.lock().await;
-                                        if writer_guard.write_all("Получен некорректный запрос на приватный чат (ошибка ключа).\n".as_bytes()).await.is_err() { break Ok(()); }
-                                        writer_guard.flush().await?;
-                                        drop(writer_guard);
-                                        log_message("Error", &format!("Неверный формат ключа в PRIVATE_CHAT_REQUEST от {}", sender_nick), Color::Red).await?;
-                                    }
-                                }
-                            } else {
-                                let mut writer_guard = writer_arc_for_task.lock().await;
-                                if writer_guard.write_all("Получен некорректный запрос на приватный чат.\n".as_bytes()).await.is_err() { break Ok(()); }
-                                writer_guard.flush().await?;
-                                drop(writer_guard);
-                                log_message("Error", &format!("Некорректный формат PRIVATE_CHAT_REQUEST: {}", msg_str), Color::Red).await?;
-                            }
-                        }
-                        "PRIVATE_CHAT_ACCEPTED" => {
-                            let originator_nick = args.to_string();
-                            let mut state_guard = client_state_write.lock().await;
-                            match &mut *state_guard {
-                                ClientState::WaitingForPrivateChatResponse { target_nick, sent_key } if target_nick == &originator_nick => {
-                                    *state_guard = ClientState::InPrivateChat { with_nick: originator_nick.clone(), shared_key: sent_key.clone() };
-                                    drop(state_guard);
-                                    let mut writer_guard = writer_arc_for_task.lock().await;
-                                    if writer_guard.write_all(format!("{} Пользователь '{}' принял ваш запрос на личный чат. Вы теперь в приватном чате.\n", colored::Colorize::green("ИНФО:"), originator_nick).as_bytes()).await.is_err() { break Ok(()); }
-                                    writer_guard.flush().await?;
-                                    drop(writer_guard);
-                                    log_message("Private chat", &format!("'{}' обновил статус: приватный чат с '{}'", nickname_write, originator_nick), Color::Cyan).await?;
-                                }
-                                _ => {
-                                    drop(state_guard);
-                                    log_message("Error", &format!("Undefined chat accept от {} для {}", originator_nick, nickname_write), Color::Red).await?;
-                                    let mut writer_guard = writer_arc_for_task.lock().await;
-                                    if writer_guard.write_all(format!("Пользователь '{}' принял ваш запрос, но вы не находитесь в ожидающем состоянии. Возможно, чат уже начат или отменен.\n", originator_nick).as_bytes()).await.is_err() { break Ok(()); }
-                                    writer_guard.flush().await?;
-                                    drop(writer_guard);
-                                }
-                            }
-                        }
-                        "PRIVATE_CHAT_REJECTED" => {
-                            let originator_nick = args.to_string();
-                            let mut state_guard = client_state_write.lock().await;
-                            match &mut *state_guard {
-                                ClientState::WaitingForPrivateChatResponse { target_nick, sent_key: _ } if target_nick == &originator_nick => {
-                                    *state_guard = ClientState::PublicChat;
-                                    drop(state_guard);
-                                    let mut writer_guard = writer_arc_for_task.lock().await;
-                                    if writer_guard.write_all(format!("{} Пользователь '{}' отклонил ваш запрос на личный чат. Вы возвращены в общий чат.\n", colored::Colorize::green("ИНФО:"), originator_nick).as_bytes()).await.is_err() { break Ok(()); }
-                                    writer_guard.flush().await?;
-                                    drop(writer_guard);
-                                    log_message("Private chat", &format!("'{}' отклонил приватный чат от '{}'", originator_nick, nickname_write), Color::Cyan).await?;
-                                }
-                                _ => {
-                                    drop(state_guard);
-                                    log_message("Error", &format!("Undefined chat reject от {} для {}", originator_nick, nickname_write), Color::Red).await?;
-                                    let mut writer_guard = writer_arc_for_task.lock().await;
-                                    if writer_guard.write_all(format!("Пользователь '{}' отклонил ваш запрос, но вы не находитесь в ожидающем состоянии. Возможно, чат уже начат или отменен.\n", originator_nick).as_bytes()).await.is_err() { break Ok(()); }
-                                    writer_guard.flush().await?;
-                                    drop(writer_guard);
-                                }
-                            }
-                        }
-                        "PRIVATE_CHAT_ENDED" => {
-                            let originator_nick = args.to_string();
-                            let mut state_guard = client_state_write.lock().await;
-                            match &mut *state_guard {
-                                ClientState::InPrivateChat { with_nick, shared_key: _ } if with_nick == &originator_nick => {
-                                    *state_guard = ClientState::PublicChat;
-                                    drop(state_guard);
-                                    let mut writer_guard = writer_arc_for_task.lock().await;
-                                    if writer_guard.write_all(format!("{} Пользователь '{}' вышел из личного чата. Вы возвращены в общий чат.\n", colored::Colorize::green("ИНФО:"), originator_nick).as_bytes()).await.is_err() { break Ok(()); }
-                                    writer_guard.flush().await?;
-                                    drop(writer_guard);
-                                    log_message("Private chat", &format!("'{}' вышел из приватного чата с '{}'", originator_nick, nickname_write), Color::Cyan).await?;
-                                }
-                                _ => {
-                                    drop(state_guard);
-                                    log_message("Error", &format!("Undefined chat end от {} для {}", originator_nick, nickname_write), Color::Red).await?;
-                                }
-                            }
-                        }
-                        "PRIVATE_CHAT_BUSY" => {
-                            let originator_nick = args.to_string();
-                            let mut state_guard = client_state_write.lock().await;
-                            match &mut *state_guard {
-                                ClientState::WaitingForPrivateChatResponse { target_nick, sent_key: _ } if target_nick == &originator_nick => {
-                                    *state_guard = ClientState::PublicChat;
-                                    drop(state_guard);
-                                    let mut writer_guard = writer_arc_for_task.lock().await;
-                                    if writer_guard.write_all(format!("{} Пользователь '{}' занят или уже в другом приватном чате. Вы возвращены в общий чат.\n", colored::Colorize::green("ИНФО:"), originator_nick).as_bytes()).await.is_err() { break Ok(()); }
-                                    writer_guard.flush().await?;
-                                    drop(writer_guard);
-                                    log_message("Private chat", &format!("'{}' занят для приватного чата с '{}'", originator_nick, nickname_write), Color::Cyan).await?;
-                                }
-                                _ => {
-                                    drop(state_guard);
-                                    log_message("Error", &format!("Undefined chat busy от {} для {}", originator_nick, nickname_write), Color::Red).await?;
-                                }
-                            }
-                        }
-                        "ENCRYPTED_PRIVATE_MSG" => {
-                            let msg_parts: Vec<&str> = args.splitn(3, ':').collect();
-                            if msg_parts.len() == 3 {
-                                let sender_nick = msg_parts[0];
-                                let nonce_hex = msg_parts[1];
-                                let ciphertext_hex = msg_parts[2];
-
-                                let mut state_guard = client_state_write.lock().await;
-                                match &mut *state_guard {
-                                    ClientState::InPrivateChat { with_nick, shared_key } if with_nick == sender_nick => {
-                                        let shared_key_clone = shared_key.clone();
-                                        drop(state_guard);
-
-                                        match hex::decode(nonce_hex) {
-                                            Ok(nonce_bytes) if nonce_bytes.len() == 12 => {
-                                                match hex::decode(ciphertext_hex) {
-                                                    Ok(ciphertext_bytes) => {
-                                                        let cipher = Aes256Gcm::new_from_slice(&shared_key_clone).expect("Key length is 32 bytes");
-                                                        let nonce = Nonce::from_slice(&nonce_bytes);
-                                                        match cipher.decrypt(nonce, ciphertext_bytes.as_ref()) {
-                                                            Ok(plaintext_bytes) => {
-                                                                if let Ok(plaintext_msg) = String::from_utf8(plaintext_bytes) {
-                                                                    let mut writer_guard = writer_arc_for_task.lock().await;
-                                                                    if writer_guard.write_all(format!("[ЛС от {}]: {}\n", colored::Colorize::cyan(sender_nick), plaintext_msg).as_bytes()).await.is_err() { break Ok(()); }
-                                                                    writer_guard.flush().await?;
-                                                                    drop(writer_guard);
-                                                                    log_message("Private", &format!("'{}' получил зашифрованное ЛС от '{}'", nickname_write, sender_nick), Color::Cyan).await?;
-                                                                } else {
-                                                                    let mut writer_guard = writer_arc_for_task.lock().await;
-                                                                    writer_guard.write_all("Получено некорректное UTF-8 сообщение (дешифровка).\n".as_bytes()).await?;
-                                                                    writer_guard.flush().await?;
-                                                                    drop(writer_guard);
-                                                                    log_message("Error", &format!("Ошибка декодирования UTF-8 для {}: {}", nickname_write, sender_nick), Color::Red).await?;
-                                                                }
-                                                            },
-                                                            Err(e) => {
-                                                                let mut writer_guard = writer_arc_for_task.lock().await;
-                                                                writer_guard.write_all("Ошибка дешифрования сообщения. Возможно, ключ неверный.\n".as_bytes()).await?;
-                                                                writer_guard.flush().await?;
-                                                                drop(writer_guard);
-                                                                log_message("Error", &format!("Ошибка дешифрования для {}: {:?}", nickname_write, e), Color::Red).await?;
-                                                            }
-                                                        }
-                                                    },
-                                                    Err(e) => {
-                                                        let mut writer_guard = writer_arc_for_task.lock().await;
-                                                        writer_guard.write_all("Получено некорректное зашифрованное сообщение (ошибка hex-декодирования).\n".as_bytes()).await?;
-                                                        writer_guard.flush().await?;
-                                                        drop(writer_guard);
-                                                        log_message("Error", &format!("Ошибка декодирования hex для ciphertext: {:?}", e), Color::Red).await?;
-                                                    }
-                                                }
-                                            },
-                                            _ => {
-                                                let mut writer_guard = writer_arc_for_task.lock().await;
-                                                writer_guard.write_all("Получено некорректное зашифрованное сообщение (ошибка hex-декодирования nonce или неверная длина).\n".as_bytes()).await?;
-                                                writer_guard.flush().await?;
-                                                drop(writer_guard);
-                                                log_message("Error", &format!("Ошибка декодирования hex для nonce или неверная длина: {:?}", nonce_hex), Color::Red).await?;
-                                            }
-                                        }
-                                    },
-                                    _ => {
-                                        drop(state_guard);
-                                        let mut writer_guard = writer_arc_for_task.lock().await;
-                                        writer_guard.write_all(format!("Получено зашифрованное сообщение от '{}', но вы не находитесь в приватном чате с ним.\n", sender_nick).as_bytes()).await?;
-                                        writer_guard.flush().await?;
-                                        drop(writer_guard);
-                                        log_message("Error", &format!("Получено ENCRYPTED_PRIVATE_MSG от {} для {} в некорректном состоянии.", sender_nick, nickname_write), Color::Red).await?;
-                                    }
-                                }
-                            } else {
-                                let mut writer_guard = writer_arc_for_task.lock().await;
-                                if writer_guard.write_all("Получено некорректное зашифрованное сообщение.\n".as_bytes()).await.is_err() { break Ok(()); }
-                                writer_guard.flush().await?;
-                                drop(writer_guard);
-                                log_message("Error", &format!("Некорректный формат ENCRYPTED_PRIVATE_MSG: {}", msg_str), Color::Red).await?;
-                            }
-                        }
-                        _ => { log_message("Error", &format!("Неизвестная системная команда: {}", command), Color::Red).await?; }
-                    }
-                } else {
-                    let display_message;
-                    {
-                        let state_guard = client_state_write.lock().await;
-                        display_message = match &*state_guard {
-                            ClientState::InPrivateChat {..} => !msg_str.starts_with(&format!("{} ", colored::Colorize::blue("Всем"))),
-                            _ => true,
-                        };
-                    }
-
-                    if display_message {
-                        let mut writer_guard = writer_arc_for_task.lock().await;
-                        if writer_guard.write_all(msg_str.as_bytes()).await.is_err() { break Ok(()); }
-                        writer_guard.flush().await?;
-                        drop(writer_guard);
-                    }
+                let dropped = rx_from_others.take_dropped();
+                if dropped > 0 {
+                    log_message(LogLevel::Warn, "outbound", &format!("'{}' не успевает получать сообщения: пропущено событий: {}", session.nickname, dropped));
+                    session.writer.send(&ServerEvent::notice("events_dropped", format!("Вы не успевали получать сообщения, пропущено: {}.", dropped))).await?;
                 }
+                session.handle_event(event).await?;
             };
             res
         }
-    });
+    };
 
+    // Сроки запросов отслеживаются в самой сессии, а не событиями через очередь:
+    // при переполнении очереди такое событие могло бы потеряться.
+    let expiry_task = {
+        let session = session.clone();
+        async move { session.run_request_timer().await }
+    };
+
+    // Отключение медленного клиента закрывает его очередь, и задача записи завершается в тот же момент:
+    // проверяется первым, чтобы сессия не ждала задачу записи, которая может висеть на сокете.
     tokio::select! {
+        biased;
+        _ = slow_disconnect => {
+            log_message(LogLevel::Debug, "outbound", &format!("{}: сессия завершается после отключения за медленное чтение.", nickname));
+        },
         res = read_task => {
-            if let Err(e) = res { log_message("SYSTEM", &format!("Ошибка в задаче чтения для {}: {:?}", nickname, e), Color::Magenta).await?; }
-            log_message("Info", &format!("{}: read_task завершилась в select.", nickname), Color::Cyan).await?;
+            if let Err(e) = res { log_message(LogLevel::Error, "client", &format!("Ошибка в задаче чтения для {}: {:?}", nickname, e)); }
+            log_message(LogLevel::Info, "client", &format!("{}: read_task завершилась в select.", nickname));
         },
         res = write_task => {
-            if let Err(e) = res { log_message("SYSTEM", &format!("Ошибка в задаче записи для {}: {:?}", nickname, e), Color::Magenta).await?; }
-            log_message("Info", &format!("{}: write_task завершилась в select.", nickname), Color::Cyan).await?;
+            if let Err(e) = res { log_message(LogLevel::Error, "client", &format!("Ошибка в задаче записи для {}: {:?}", nickname, e)); }
+            log_message(LogLevel::Info, "client", &format!("{}: write_task завершилась в select.", nickname));
+        },
+        res = expiry_task => {
+            if let Err(e) = res { log_message(LogLevel::Error, "client", &format!("Ошибка в задаче сроков запросов для {}: {:?}", nickname, e)); }
         },
     }
 
-    let final_client_state = client_state.lock().await.clone();
+    let final_client_state = session.client_state.lock().await.clone();
     {
         let mut users_guard = connected_users.lock().await;
-        users_guard.remove(&nickname);
-        log_message("Client", &format!("Пользователь '{}' отключился. В сети: {}", nickname, users_guard.len()), Color::Yellow).await?;
+        // После /kick запись уже удалена и могла смениться новой сессией того же пользователя,
+        // поэтому удаляется только своя очередь.
+        if users_guard.get(&nickname).is_some_and(|tx| tx.same_queue(&session.events)) {
+            users_guard.remove(&nickname);
+        }
+        log_message(LogLevel::Info, "client", &format!("Пользователь '{}' отключился. В сети: {}", nickname, users_guard.len()));
     }
 
-    if let ClientState::InPrivateChat { with_nick, shared_key: _ } = final_client_state {
-        let _ = send_to_user(&connected_users, &with_nick, format!("SYSTEM:PRIVATE_CHAT_ENDED:{}", nickname)).await;
-        log_message("Info", &format!("Уведомлен '{}' о выходе '{}' из их приватного чата", with_nick, nickname), Color::Cyan).await?;
+    // При остановке сервера очереди собеседников уже закрыты, поэтому о завершении чатов
+    // каждый клиент узнаёт от своей сессии перед закрытием соединения.
+    if state.is_shutting_down() {
+        for partner_nick in final_client_state.private_chats.into_keys() {
+            let _ = session.writer.send(&ServerEvent::PrivateChatEnded { from: partner_nick }).await;
+        }
+        return Ok(());
     }
 
-    let leave_msg = format!("Пользователь '{}' вышел из чата", nickname);
-    broadcast_message(&connected_users, &nickname, &leave_msg, true).await;
+    for (partner_nick, status) in final_client_state.private_chats {
+        let notice = match status {
+            PrivateChatStatus::PendingRequest => ServerEvent::PrivateChatRejected { from: nickname.clone() },
+            PrivateChatStatus::WaitingForResponse | PrivateChatStatus::Active => ServerEvent::PrivateChatEnded { from: nickname.clone() },
+        };
+        let _ = send_to_user(&connected_users, &partner_nick, notice).await;
+        log_message(LogLevel::Info, "private_chat", &format!("Уведомлен '{}' о выходе '{}' из их приватного чата", partner_nick, nickname));
+    }
+
+    let updated_groups = state.groups.lock().await.remove_user(&nickname);
+    for info in updated_groups {
+        announce_group(&connected_users, info).await;
+    }
+
+    let last_room = state.rooms.lock().await.remove(&nickname);
+    if let Some(room) = last_room {
+        broadcast_to_room(&connected_users, &state.rooms, &room, &nickname, ServerEvent::UserLeft { nick: nickname.clone(), room: room.clone() }).await;
+    }
     Ok(())
 }
+
+impl Session {
+    async fn handle_command(&self, command: ClientCommand) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match command {
+            ClientCommand::Help => self.cmd_help().await,
+            ClientCommand::List => self.cmd_list().await,
+            ClientCommand::Pm { nick, public_key } => self.cmd_pm(nick.trim(), public_key).await,
+            ClientCommand::Accept { nick, public_key } => self.cmd_accept(nick.as_deref(), public_key).await,
+            ClientCommand::Reject { nick } => self.cmd_reject(nick.as_deref()).await,
+            ClientCommand::Requests => self.cmd_requests().await,
+            ClientCommand::Cancel { nick } => self.cmd_cancel(nick.as_deref()).await,
+            ClientCommand::Switch { nick } => self.cmd_switch(nick.as_deref()).await,
+            ClientCommand::Chats => self.cmd_chats().await,
+            ClientCommand::Exit => {
+                if !self.exit_private_chat().await? {
+                    self.writer.send(&ServerEvent::error("not_in_private_chat", "Сейчас ввод не в личном чате.")).await?;
+                }
+                Ok(())
+            }
+            ClientCommand::Join { room } => self.cmd_join(&room).await,
+            ClientCommand::Leave => self.cmd_leave().await,
+            ClientCommand::Rooms => self.cmd_rooms().await,
+            ClientCommand::Who { room } => self.cmd_who(room.as_deref()).await,
+            ClientCommand::History { limit } => self.cmd_history(limit).await,
+            ClientCommand::Say { text } => {
+                let text = text.trim();
+                if text.is_empty() {
+                    self.writer.send(&ServerEvent::error("empty_message", "Сообщение не может быть пустым.")).await?;
+                    return Ok(());
+                }
+                if !self.check_single_line(text).await? {
+                    return Ok(());
+                }
+                self.send_text(text, false).await
+            }
+            ClientCommand::Dm { to, text } => {
+                if !self.check_single_line(&text).await? {
+                    return Ok(());
+                }
+                self.send_direct(to.trim(), text.trim()).await
+            }
+            ClientCommand::Encrypted { to, nonce, ciphertext } => self.send_encrypted(to.trim(), nonce, ciphertext).await,
+            ClientCommand::Line(text) => {
+                if text.to_lowercase() == "выход" && self.exit_private_chat().await? {
+                    return Ok(());
+                }
+                self.send_text(&text, true).await
+            }
+            ClientCommand::GroupCreate { group, public_key } => self.cmd_group_create(&group, public_key).await,
+            ClientCommand::GroupInvite { group, nick } => self.cmd_group_invite(&group, nick.trim()).await,
+            ClientCommand::GroupAccept { group, public_key } => self.cmd_group_accept(&group, public_key).await,
+            ClientCommand::GroupDecline { group } => self.cmd_group_decline(&group).await,
+            ClientCommand::GroupLeave { group } => self.cmd_group_leave(&group).await,
+            ClientCommand::GroupList => self.cmd_group_list().await,
+            ClientCommand::GroupKey { group, epoch, keys } => self.cmd_group_key(&group, epoch, keys).await,
+            ClientCommand::GroupMsg { group, epoch, nonce, ciphertext } => self.send_group_message(&group, epoch, nonce, ciphertext).await,
+            ClientCommand::Kick { nick, reason } => {
+                if !self.check_single_line(reason.as_deref().unwrap_or_default()).await? {
+                    return Ok(());
+                }
+                self.cmd_kick(nick.trim(), reason).await
+            }
+            ClientCommand::Ban { nick, reason } => {
+                if !self.check_single_line(reason.as_deref().unwrap_or_default()).await? {
+                    return Ok(());
+                }
+                self.cmd_ban(nick.trim(), reason).await
+            }
+            ClientCommand::Unban { nick } => self.cmd_unban(nick.trim()).await,
+            ClientCommand::Mute { nick, seconds } => self.cmd_mute(nick.trim(), seconds).await,
+            ClientCommand::Unmute { nick } => self.cmd_unmute(nick.trim()).await,
+            ClientCommand::Reload => self.cmd_reload().await,
+            ClientCommand::LogLevel { level } => self.cmd_log_level(level.as_deref()).await,
+            ClientCommand::Answer { .. } => {
+                self.writer.send(&ServerEvent::error("unexpected_answer", "Сейчас сервер не ожидает ответа.")).await?;
+                Ok(())
+            }
+            ClientCommand::Unknown(command) => {
+                self.writer.send(&ServerEvent::error("unknown_command", format!("Неизвестная команда: '{}'. Введите /help.", command))).await?;
+                log_message(LogLevel::Debug, "command", &format!("'{}' ввел неизвестную команду: '{}'", self.nickname, command));
+                Ok(())
+            }
+        }
+    }
+
+    // В JSON-команде текст может содержать перевод строки, и клиенту текстового режима
+    // одно сообщение пришло бы несколькими строками, в том числе поддельными.
+    async fn check_single_line(&self, text: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        if text.contains(['\n', '\r']) {
+            self.writer.send(&ServerEvent::error("multiline_text", "Текст не может содержать переводы строк.")).await?;
+            log_message(LogLevel::Debug, "command", &format!("'{}' прислал текст с переводом строки.", self.nickname));
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    async fn cmd_help(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.writer.send(&ServerEvent::Help { commands: help_entries(self.server.config().is_admin(&self.nickname)) }).await?;
+        log_message(LogLevel::Debug, "command", &format!("'{}' запросил /help", self.nickname));
+        Ok(())
+    }
+
+    async fn cmd_list(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let users = self.server.connected_users.lock().await;
+        let mut connected_list: Vec<String> = users.keys()
+            .filter(|name| *name != &self.nickname)
+            .cloned()
+            .collect();
+        drop(users);
+        connected_list.sort();
+
+        let users_for_log = connected_list.join(", ");
+        self.writer.send(&ServerEvent::UserList { users: connected_list }).await?;
+        log_message(LogLevel::Debug, "command", &format!("'{}' запросил /list. Онлайн пользователи: {}", self.nickname, users_for_log));
+        Ok(())
+    }
+
+    async fn cmd_join(&self, room: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(room) = normalize_room_name(room) else {
+            self.writer.send(&ServerEvent::error("bad_room_name", "Имя комнаты: латиница, цифры, '-' и '_', до 32 символов. Например: /join #dev")).await?;
+            return Ok(());
+        };
+        if self.server.rooms.lock().await.room_of(&self.nickname) == Some(room.as_str()) {
+            self.writer.send(&ServerEvent::notice("already_in_room", format!("Вы уже в комнате {}.", room))).await?;
+            return Ok(());
+        }
+        self.move_to_room(&room).await
+    }
+
+    async fn cmd_leave(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let rooms = self.server.rooms.lock().await;
+        let default_room = rooms.default_room().to_string();
+        let in_default_room = rooms.room_of(&self.nickname) == Some(default_room.as_str());
+        drop(rooms);
+        if in_default_room {
+            self.writer.send(&ServerEvent::error("leave_default_room", format!("Вы и так в комнате по умолчанию {}.", default_room))).await?;
+            return Ok(());
+        }
+        self.move_to_room(&default_room).await
+    }
+
+    async fn cmd_rooms(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let rooms = self.server.rooms.lock().await.list();
+        self.writer.send(&ServerEvent::RoomList { rooms }).await?;
+        log_message(LogLevel::Debug, "command", &format!("'{}' запросил /rooms", self.nickname));
+        Ok(())
+    }
+
+    async fn cmd_who(&self, room: Option<&str>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let rooms = self.server.rooms.lock().await;
+        let room = match room {
+            Some(room) => normalize_room_name(room),
+            None => rooms.room_of(&self.nickname).map(str::to_string),
+        };
+        let members = room.as_deref().and_then(|room| rooms.members(room));
+        drop(rooms);
+
+        match (room, members) {
+            (Some(room), Some(users)) => self.writer.send(&ServerEvent::RoomMembers { room, users }).await?,
+            _ => self.writer.send(&ServerEvent::error("room_not_found", "Такой комнаты нет. Список комнат: /rooms")).await?,
+        }
+        Ok(())
+    }
+
+    async fn cmd_history(&self, limit: Option<usize>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let limit = limit.unwrap_or(DEFAULT_HISTORY_REPLAY);
+        if limit == 0 || limit > MAX_HISTORY_REPLAY {
+            self.writer.send(&ServerEvent::error("history_usage", format!("Использование: /history [N], где N от 1 до {}.", MAX_HISTORY_REPLAY))).await?;
+            return Ok(());
+        }
+        let room = self.server.rooms.lock().await.room_of(&self.nickname).unwrap_or_default().to_string();
+        let entries = self.server.history.recent(&room, limit).await;
+        self.writer.send(&ServerEvent::History { room, entries }).await?;
+        log_message(LogLevel::Debug, "command", &format!("'{}' запросил /history {}", self.nickname, limit));
+        Ok(())
+    }
+
+    // Переход в комнату: уведомляет участников старой и новой комнаты и присылает клиенту состав новой.
+    async fn move_to_room(&self, room: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut rooms = self.server.rooms.lock().await;
+        let previous = rooms.join(&self.nickname, room);
+        let mut users = rooms.members(room).unwrap_or_default();
+        drop(rooms);
+        users.retain(|nick| nick != &self.nickname);
+
+        let connected_users = &self.server.connected_users;
+        if let Some(previous) = previous {
+            broadcast_to_room(connected_users, &self.server.rooms, &previous, &self.nickname, ServerEvent::UserLeft { nick: self.nickname.clone(), room: previous.clone() }).await;
+        }
+        broadcast_to_room(connected_users, &self.server.rooms, room, &self.nickname, ServerEvent::UserJoined { nick: self.nickname.clone(), room: room.to_string() }).await;
+        self.writer.send(&ServerEvent::RoomJoined { room: room.to_string(), users }).await?;
+        log_message(LogLevel::Info, "room", &format!("'{}' перешёл в комнату {}", self.nickname, room));
+        Ok(())
+    }
+
+    async fn cmd_pm(&self, target_nick: &str, public_key: Vec<u8>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.allow(Action::Request).await? {
+            return Ok(());
+        }
+        if target_nick.is_empty() {
+            self.writer.send(&ServerEvent::error("pm_usage", "Укажите ник пользователя для личного чата: /pm <ник> <ключ>")).await?;
+            log_message(LogLevel::Debug, "command", &format!("'{}' ввел /pm без цели.", self.nickname));
+            return Ok(());
+        }
+        if target_nick == self.nickname {
+            self.writer.send(&ServerEvent::error("pm_self", "Вы не можете начать личный чат с самим собой.")).await?;
+            log_message(LogLevel::Debug, "command", &format!("'{}' пытался начать /pm с самим собой.", self.nickname));
+            return Ok(());
+        }
+        if !self.check_public_key(&public_key).await? {
+            return Ok(());
+        }
+
+        let mut state_guard = self.client_state.lock().await;
+        if let Some(status) = state_guard.private_chats.get(target_nick).copied() {
+            drop(state_guard);
+            self.writer.send(&ServerEvent::error("pm_exists", format!("С '{}' уже есть личный чат или запрос на него.", target_nick))).await?;
+            log_message(LogLevel::Warn, "private_chat", &format!("'{}' повторно запросил ЛС с '{}' (состояние: {:?})", self.nickname, target_nick, status));
+            return Ok(());
+        }
+        state_guard.private_chats.insert(target_nick.to_string(), PrivateChatStatus::WaitingForResponse);
+        self.start_request_timer(&mut state_guard, target_nick);
+        drop(state_guard);
+
+        let request = ServerEvent::PrivateChatRequest { from: self.nickname.clone(), public_key };
+        if send_to_user(&self.server.connected_users, target_nick, request).await.is_ok() {
+            self.writer.send(&ServerEvent::notice("pm_sent", format!("Запрос на личный чат отправлен пользователю '{}'. Ожидание ответа...", target_nick))).await?;
+            log_message(LogLevel::Info, "private_chat", &format!("'{}' запросил приватный чат у '{}'", self.nickname, target_nick));
+        } else {
+            self.forget_private_chat(target_nick).await;
+            self.writer.send(&ServerEvent::error("user_offline", format!("Пользователь '{}' не найден или не в сети.", target_nick))).await?;
+            log_message(LogLevel::Warn, "private_chat", &format!("'{}' пытался запросить приватный чат у оффлайн пользователя '{}'", self.nickname, target_nick));
+        }
+        Ok(())
+    }
+
+    async fn cmd_accept(&self, nick: Option<&str>, public_key: Vec<u8>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.check_public_key(&public_key).await? {
+            return Ok(());
+        }
+        let mut state_guard = self.client_state.lock().await;
+        let Some(partner_nick) = state_guard.pending_request(nick) else {
+            drop(state_guard);
+            self.send_no_pending_request(nick).await?;
+            log_message(LogLevel::Debug, "command", &format!("'{}' пытался /accept без ожидающего запроса.", self.nickname));
+            return Ok(());
+        };
+        state_guard.remove(&partner_nick);
+        state_guard.private_chats.insert(partner_nick.clone(), PrivateChatStatus::Active);
+        state_guard.focus = Some(partner_nick.clone());
+        drop(state_guard);
+
+        let accepted = ServerEvent::PrivateChatAccepted { from: self.nickname.clone(), public_key };
+        if send_to_user(&self.server.connected_users, &partner_nick, accepted).await.is_ok() {
+            self.writer.send(&ServerEvent::notice("private_chat_started", format!("Вы начали личный чат с '{}'. Напишите 'выход', чтобы закончить его.", partner_nick))).await?;
+            self.writer.send(&ServerEvent::FocusChanged { partner: Some(partner_nick.clone()) }).await?;
+            log_message(LogLevel::Info, "private_chat", &format!("'{}' обновил статус: приватный чат с '{}'", self.nickname, partner_nick));
+        } else {
+            self.forget_private_chat(&partner_nick).await;
+            self.writer.send(&ServerEvent::error("partner_unreachable", format!("Не удалось уведомить '{}', возможно, он отключился.", partner_nick))).await?;
+            log_message(LogLevel::Warn, "private_chat", &format!("'{}' принял приватный чат от '{}', но не смог уведомить партнера.", self.nickname, partner_nick));
+        }
+        Ok(())
+    }
+
+    async fn cmd_reject(&self, nick: Option<&str>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut state_guard = self.client_state.lock().await;
+        let Some(partner_nick) = state_guard.pending_request(nick) else {
+            drop(state_guard);
+            self.send_no_pending_request(nick).await?;
+            log_message(LogLevel::Debug, "command", &format!("'{}' пытался /reject без ожидающего запроса.", self.nickname));
+            return Ok(());
+        };
+        state_guard.remove(&partner_nick);
+        drop(state_guard);
+
+        if send_to_user(&self.server.connected_users, &partner_nick, ServerEvent::PrivateChatRejected { from: self.nickname.clone() }).await.is_ok() {
+            self.writer.send(&ServerEvent::notice("private_chat_rejected", format!("Вы отклонили запрос на личный чат от '{}'.", partner_nick))).await?;
+            log_message(LogLevel::Info, "private_chat", &format!("'{}' отклонил приватный чат от '{}'", self.nickname, partner_nick));
+        } else {
+            self.writer.send(&ServerEvent::error("partner_unreachable", format!("Не удалось уведомить '{}' об отклонении, возможно, он отключился.", partner_nick))).await?;
+            log_message(LogLevel::Warn, "private_chat", &format!("'{}' отклонил приватный чат от '{}', но не смог уведомить партнера.", self.nickname, partner_nick));
+        }
+        Ok(())
+    }
+
+    async fn send_no_pending_request(&self, nick: Option<&str>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let error = match nick {
+            Some(nick) => ServerEvent::error("no_pending_request", format!("Нет запроса на личный чат от '{}'. Список запросов: /requests", nick)),
+            None => ServerEvent::error("no_pending_request", "Нет входящих запросов на личный чат."),
+        };
+        self.writer.send(&error).await?;
+        Ok(())
+    }
+
+    async fn cmd_requests(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let requests = self.client_state.lock().await.incoming_requests.iter().cloned().collect();
+        self.writer.send(&ServerEvent::PrivateChatRequests { requests }).await?;
+        Ok(())
+    }
+
+    // Без ника отменяет единственный исходящий запрос.
+    async fn cmd_cancel(&self, nick: Option<&str>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let outgoing = self.client_state.lock().await.outgoing_requests();
+        let partner_nick = match nick.map(str::trim).filter(|nick| !nick.is_empty()) {
+            Some(nick) if outgoing.iter().any(|partner| partner == nick) => nick.to_string(),
+            Some(nick) => {
+                self.writer.send(&ServerEvent::error("no_outgoing_request", format!("Вы не отправляли запрос на личный чат пользователю '{}'.", nick))).await?;
+                return Ok(());
+            }
+            None if outgoing.len() == 1 => outgoing[0].clone(),
+            None if outgoing.is_empty() => {
+                self.writer.send(&ServerEvent::error("no_outgoing_request", "У вас нет запросов на личный чат, ожидающих ответа.")).await?;
+                return Ok(());
+            }
+            None => {
+                self.writer.send(&ServerEvent::error("cancel_usage", format!("Укажите, какой запрос отменить: /cancel <ник>. Ожидают ответа: {}", outgoing.join(", ")))).await?;
+                return Ok(());
+            }
+        };
+        self.forget_private_chat(&partner_nick).await;
+
+        let _ = send_to_user(&self.server.connected_users, &partner_nick, ServerEvent::PrivateChatCancelled { from: self.nickname.clone() }).await;
+        self.writer.send(&ServerEvent::notice("pm_cancelled", format!("Запрос на личный чат к '{}' отменён.", partner_nick))).await?;
+        log_message(LogLevel::Info, "private_chat", &format!("'{}' отменил запрос на приватный чат к '{}'", self.nickname, partner_nick));
+        Ok(())
+    }
+
+    // Запоминает срок ответа на исходящий запрос; истечёт он в задаче сроков этой сессии.
+    fn start_request_timer(&self, client_state: &mut ClientState, partner_nick: &str) {
+        let timeout = self.server.config().private_request_timeout;
+        if timeout == 0 {
+            return;
+        }
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        client_state.request_deadlines.insert(partner_nick.to_string(), deadline);
+        self.request_timer.notify_one();
+    }
+
+    // Ждёт ближайшего срока запроса; новый срок, добавленный раньше него, будит задачу заново.
+    async fn run_request_timer(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let next_deadline = self.client_state.lock().await.request_deadlines.values().min().copied();
+            match next_deadline {
+                Some(deadline) => tokio::select! {
+                    _ = tokio::time::sleep_until(deadline) => self.expire_requests().await?,
+                    _ = self.request_timer.notified() => {}
+                },
+                None => self.request_timer.notified().await,
+            }
+        }
+    }
+
+    // Снимает наступившие сроки; запросы, на которые так и не ответили, отменяются у обеих сторон.
+    async fn expire_requests(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        {
+            let mut state_guard = self.client_state.lock().await;
+            let due: Vec<String> = state_guard
+                .request_deadlines
+                .iter()
+                .filter(|(_, deadline)| **deadline <= now)
+                .map(|(partner, _)| partner.clone())
+                .collect();
+            for partner in due {
+                state_guard.request_deadlines.remove(&partner);
+                if state_guard.private_chats.get(&partner) == Some(&PrivateChatStatus::WaitingForResponse) {
+                    state_guard.remove(&partner);
+                    expired.push(partner);
+                }
+            }
+        }
+        for partner in expired {
+            let _ = send_to_user(&self.server.connected_users, &partner, ServerEvent::PrivateChatExpired { partner: self.nickname.clone() }).await;
+            self.writer.send(&ServerEvent::PrivateChatExpired { partner: partner.clone() }).await?;
+            log_message(LogLevel::Info, "private_chat", &format!("Запрос на приватный чат между '{}' и '{}' истёк", self.nickname, partner));
+        }
+        Ok(())
+    }
+
+    // Без ника фокус возвращается в текущую комнату.
+    async fn cmd_switch(&self, nick: Option<&str>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut state_guard = self.client_state.lock().await;
+        let partner = match nick.map(str::trim).filter(|nick| !nick.is_empty()) {
+            Some(nick) if state_guard.private_chats.get(nick) == Some(&PrivateChatStatus::Active) => Some(nick.to_string()),
+            Some(nick) => {
+                drop(state_guard);
+                self.writer.send(&ServerEvent::error("no_private_chat", format!("У вас нет личного чата с '{}'. Список чатов: /chats", nick))).await?;
+                return Ok(());
+            }
+            None => None,
+        };
+        state_guard.focus = partner.clone();
+        drop(state_guard);
+        self.writer.send(&ServerEvent::FocusChanged { partner }).await?;
+        Ok(())
+    }
+
+    async fn cmd_chats(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let state_guard = self.client_state.lock().await;
+        let chats = state_guard
+            .private_chats
+            .iter()
+            .map(|(nick, status)| PrivateChatInfo { nick: nick.clone(), status: *status })
+            .collect();
+        let focus = state_guard.focus.clone();
+        drop(state_guard);
+        self.writer.send(&ServerEvent::PrivateChats { chats, focus }).await?;
+        Ok(())
+    }
+
+    // Убирает собеседника из таблицы; если разговор был активным, фокус возвращается в комнату.
+    async fn forget_private_chat(&self, partner_nick: &str) -> bool {
+        let mut state_guard = self.client_state.lock().await;
+        state_guard.remove(partner_nick);
+        if state_guard.focus.as_deref() == Some(partner_nick) {
+            state_guard.focus = None;
+            return true;
+        }
+        false
+    }
+
+    // Завершает активный разговор. Возвращает false, если фокус не на личном чате.
+    async fn exit_private_chat(&self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let Some(partner_nick) = self.client_state.lock().await.focus.clone() else {
+            return Ok(false);
+        };
+        self.forget_private_chat(&partner_nick).await;
+
+        let _ = send_to_user(&self.server.connected_users, &partner_nick, ServerEvent::PrivateChatEnded { from: self.nickname.clone() }).await;
+        self.writer.send(&ServerEvent::notice("private_chat_left", format!("Вы вышли из личного чата с '{}'.", partner_nick))).await?;
+        self.writer.send(&ServerEvent::FocusChanged { partner: None }).await?;
+        log_message(LogLevel::Info, "private_chat", &format!("'{}' вышел из приватного чата с '{}'", self.nickname, partner_nick));
+        Ok(true)
+    }
+
+    // Открытый ключ X25519 клиента, сервер проверяет только его длину.
+    async fn check_public_key(&self, public_key: &[u8]) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        if public_key.len() == PUBLIC_KEY_LEN {
+            return Ok(true);
+        }
+        self.writer.send(&ServerEvent::error("bad_public_key", format!("Нужен открытый ключ X25519 длиной {} байта в hex. Используйте kursovik-client.", PUBLIC_KEY_LEN))).await?;
+        log_message(LogLevel::Debug, "command", &format!("'{}' передал открытый ключ неверной длины: {}", self.nickname, public_key.len()));
+        Ok(false)
+    }
+
+    // Обычный текст уходит в текущую комнату. Если фокус на личном чате, сервер принимает только шифртекст.
+    // В текстовом режиме строка вида "ник: текст" считается прямым сообщением.
+    async fn send_text(&self, text: &str, allow_direct: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let focus = self.client_state.lock().await.focus.clone();
+        if let Some(partner_nick) = focus {
+            self.writer.send(&ServerEvent::error("encryption_required", format!("Сообщения в личном чате с '{}' шифруются на стороне клиента. Используйте kursovik-client или /encrypted.", partner_nick))).await?;
+            log_message(LogLevel::Debug, "client", &format!("'{}' пытался отправить открытый текст в приватный чат.", self.nickname));
+            return Ok(());
+        }
+
+        if let Some((recipient, message_content)) = text.split_once(':').filter(|_| allow_direct) {
+            self.send_direct(recipient.trim(), message_content.trim()).await?;
+        } else {
+            if !self.allow(Action::Message).await? {
+                return Ok(());
+            }
+            if let Some(until) = self.server.moderation.lock().await.muted_until(&self.nickname) {
+                self.writer.send(&ServerEvent::error("muted", format!("Вы не можете писать в комнаты до {}.", format_time(until)))).await?;
+                return Ok(());
+            }
+            let room = self.server.rooms.lock().await.room_of(&self.nickname).unwrap_or_default().to_string();
+            if let Err(e) = self.server.history.record(&room, &self.nickname, text).await {
+                log_message(LogLevel::Error, "history", &format!("Не удалось сохранить сообщение в историю: {}", e));
+            }
+            let message = ServerEvent::ChatMessage { room: room.clone(), from: self.nickname.clone(), text: text.to_string() };
+            broadcast_to_room(&self.server.connected_users, &self.server.rooms, &room, &self.nickname, message).await;
+        }
+        Ok(())
+    }
+
+    // Сообщения удаляются из ящика только после того, как ушли клиенту.
+    async fn deliver_offline_messages(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let messages = self.server.inbox.lock().await.pending(&self.nickname);
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let count = messages.len();
+        self.writer.send(&ServerEvent::OfflineMessages { messages }).await?;
+
+        let mut inbox = self.server.inbox.lock().await;
+        inbox.acknowledge(&self.nickname, count);
+        inbox.save().await?;
+        drop(inbox);
+        log_user_message(LogLevel::Info, "message", &format!("'{}' получил {} сообщений, пришедших, пока он был не в сети", self.nickname, count), None, &[]);
+        Ok(())
+    }
+
+    async fn send_direct(&self, recipient: &str, message_content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.allow(Action::Direct).await? {
+            return Ok(());
+        }
+        if recipient == self.nickname {
+            self.writer.send(&ServerEvent::error("dm_self", "Вы не можете отправить ЛС самому себе.")).await?;
+            log_message(LogLevel::Warn, "message", &format!("'{}' пытался отправить ЛС самому себе.", self.nickname));
+            return Ok(());
+        }
+
+        // В сети бывают только зарегистрированные, поэтому базу можно проверить заранее.
+        // Ящик при этом ещё не захвачен: остановка сервера берёт базу раньше ящика.
+        let registered = self.server.users_db.lock().await.contains_key(recipient);
+        if !registered {
+            self.writer.send(&ServerEvent::error("user_offline", format!("Ошибка: Пользователь '{}' не найден или не в сети.", recipient))).await?;
+            log_message(LogLevel::Warn, "message", &format!("'{}' не смог отправить прямое сообщение незарегистрированному пользователю '{}'", self.nickname, recipient));
+            return Ok(());
+        }
+
+        // Ящик блокируется до попытки отправки: получатель забирает свои сообщения уже после
+        // появления в connected_users, поэтому сообщение не застрянет в ящике, пока он в сети.
+        let mut inbox = self.server.inbox.lock().await;
+        let direct_msg = ServerEvent::DirectMessage { from: self.nickname.clone(), text: message_content.to_string() };
+        if send_to_user(&self.server.connected_users, recipient, direct_msg).await.is_ok() {
+            drop(inbox);
+            log_user_message(LogLevel::Info, "message", &format!("'{}' отправил прямое сообщение '{}'", self.nickname, recipient), Some(message_content), &[]);
+            return Ok(());
+        }
+        if !inbox.push(recipient, &self.nickname, message_content) {
+            drop(inbox);
+            self.writer.send(&ServerEvent::error("inbox_full", format!("Пользователь '{}' не в сети, и его ящик переполнен. Попробуйте позже.", recipient))).await?;
+            log_message(LogLevel::Info, "message", &format!("Ящик '{}' переполнен, сообщение от '{}' отклонено", recipient, self.nickname));
+            return Ok(());
+        }
+        inbox.save().await?;
+        drop(inbox);
+        self.writer.send(&ServerEvent::notice("dm_queued", format!("Пользователь '{}' не в сети. Сообщение будет доставлено, когда он войдёт.", recipient))).await?;
+        log_user_message(LogLevel::Info, "message", &format!("'{}' оставил сообщение пользователю '{}', который не в сети", self.nickname, recipient), Some(message_content), &[]);
+        Ok(())
+    }
+
+    // Сервер не знает ключа и пересылает шифртекст собеседнику как есть.
+    // Отправлять можно в любой активный личный чат, не только в тот, что в фокусе.
+    async fn send_encrypted(&self, to: &str, nonce: Vec<u8>, ciphertext: Vec<u8>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.allow(Action::Direct).await? {
+            return Ok(());
+        }
+        if !self.client_state.lock().await.is_active(to) {
+            self.writer.send(&ServerEvent::error("not_in_private_chat", format!("Вы не находитесь в приватном чате с '{}'.", to))).await?;
+            return Ok(());
+        }
+        if nonce.len() != NONCE_LEN {
+            self.writer.send(&ServerEvent::error("bad_encrypted_message", format!("Nonce должен занимать {} байт.", NONCE_LEN))).await?;
+            log_message(LogLevel::Warn, "private_chat", &format!("Неверная длина nonce от {}: {}", self.nickname, nonce.len()));
+            return Ok(());
+        }
+
+        let encrypted_msg = ServerEvent::EncryptedPrivateMsg { from: self.nickname.clone(), nonce, ciphertext };
+        if send_to_user(&self.server.connected_users, to, encrypted_msg).await.is_ok() {
+            log_user_message(LogLevel::Info, "private_chat", &format!("'{}' отправил зашифрованное ЛС '{}'", self.nickname, to), None, &[]);
+        } else {
+            let focus_lost = self.forget_private_chat(to).await;
+            self.writer.send(&ServerEvent::error("partner_unreachable", format!("Не удалось отправить сообщение '{}'. Возможно, пользователь отключился. Личный чат закрыт.", to))).await?;
+            if focus_lost {
+                self.writer.send(&ServerEvent::FocusChanged { partner: None }).await?;
+            }
+            log_message(LogLevel::Warn, "private_chat", &format!("'{}' не смог отправить зашифрованное ЛС '{}'. Партнер отключился.", self.nickname, to));
+        }
+        Ok(())
+    }
+
+    async fn group_name(&self, group: &str) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let name = normalize_group_name(group);
+        if name.is_none() {
+            self.writer.send(&ServerEvent::error("bad_group_name", "Имя группы: латиница, цифры, '-' и '_', до 32 символов. Например: /group create &team <ключ>")).await?;
+        }
+        Ok(name)
+    }
+
+    async fn send_group_error(&self, group: &str, error: GroupError) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.writer.send(&ServerEvent::error(error.code(), error.text(group))).await?;
+        log_message(LogLevel::Warn, "group", &format!("'{}': ошибка в группе {}: {:?}", self.nickname, group, error));
+        Ok(())
+    }
+
+    async fn cmd_group_create(&self, group: &str, public_key: Vec<u8>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(group) = self.group_name(group).await? else {
+            return Ok(());
+        };
+        if !self.check_public_key(&public_key).await? {
+            return Ok(());
+        }
+        let created = self.server.groups.lock().await.create(&group, &self.nickname, public_key);
+        match created {
+            Ok(info) => {
+                self.writer.send(&ServerEvent::GroupUpdated { group: info }).await?;
+                log_message(LogLevel::Info, "group", &format!("'{}' создал группу {}", self.nickname, group));
+            }
+            Err(e) => self.send_group_error(&group, e).await?,
+        }
+        Ok(())
+    }
+
+    async fn cmd_group_invite(&self, group: &str, nick: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.allow(Action::Request).await? {
+            return Ok(());
+        }
+        let Some(group) = self.group_name(group).await? else {
+            return Ok(());
+        };
+        if nick.is_empty() || nick == self.nickname {
+            self.writer.send(&ServerEvent::error("group_invite_usage", "Укажите, кого пригласить: /group invite <группа> <ник>")).await?;
+            return Ok(());
+        }
+        let invited = self.server.groups.lock().await.invite(&group, &self.nickname, nick);
+        if let Err(e) = invited {
+            return self.send_group_error(&group, e).await;
+        }
+
+        let invite = ServerEvent::GroupInvite { group: group.clone(), from: self.nickname.clone() };
+        if send_to_user(&self.server.connected_users, nick, invite).await.is_ok() {
+            self.writer.send(&ServerEvent::notice("group_invited", format!("Приглашение в группу {} отправлено пользователю '{}'.", group, nick))).await?;
+            log_message(LogLevel::Info, "group", &format!("'{}' пригласил '{}' в группу {}", self.nickname, nick, group));
+        } else {
+            let _ = self.server.groups.lock().await.decline(&group, nick);
+            self.writer.send(&ServerEvent::error("user_offline", format!("Пользователь '{}' не найден или не в сети.", nick))).await?;
+        }
+        Ok(())
+    }
+
+    async fn cmd_group_accept(&self, group: &str, public_key: Vec<u8>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(group) = self.group_name(group).await? else {
+            return Ok(());
+        };
+        if !self.check_public_key(&public_key).await? {
+            return Ok(());
+        }
+        let accepted = self.server.groups.lock().await.accept(&group, &self.nickname, public_key);
+        match accepted {
+            Ok(info) => {
+                announce_group(&self.server.connected_users, info).await;
+                log_message(LogLevel::Info, "group", &format!("'{}' вступил в группу {}", self.nickname, group));
+            }
+            Err(e) => self.send_group_error(&group, e).await?,
+        }
+        Ok(())
+    }
+
+    async fn cmd_group_decline(&self, group: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(group) = self.group_name(group).await? else {
+            return Ok(());
+        };
+        let declined = self.server.groups.lock().await.decline(&group, &self.nickname);
+        match declined {
+            Ok(owner) => {
+                let _ = send_to_user(&self.server.connected_users, &owner, ServerEvent::GroupInviteDeclined { group: group.clone(), from: self.nickname.clone() }).await;
+                self.writer.send(&ServerEvent::notice("group_declined", format!("Вы отклонили приглашение в группу {}.", group))).await?;
+            }
+            Err(e) => self.send_group_error(&group, e).await?,
+        }
+        Ok(())
+    }
+
+    async fn cmd_group_leave(&self, group: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(group) = self.group_name(group).await? else {
+            return Ok(());
+        };
+        let left = self.server.groups.lock().await.leave(&group, &self.nickname);
+        match left {
+            Ok(remaining) => {
+                if let Some(info) = remaining {
+                    announce_group(&self.server.connected_users, info).await;
+                }
+                self.writer.send(&ServerEvent::notice("group_left", format!("Вы вышли из группы {}.", group))).await?;
+                log_message(LogLevel::Info, "group", &format!("'{}' вышел из группы {}", self.nickname, group));
+            }
+            Err(e) => self.send_group_error(&group, e).await?,
+        }
+        Ok(())
+    }
+
+    async fn cmd_group_list(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let groups = self.server.groups.lock().await.groups_of(&self.nickname);
+        self.writer.send(&ServerEvent::Groups { groups }).await?;
+        Ok(())
+    }
+
+    // Владелец раздаёт ключ новой эпохи; сервер пересылает каждому участнику только его долю.
+    async fn cmd_group_key(&self, group: &str, epoch: u64, keys: Vec<GroupKeyShare>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(group) = self.group_name(group).await? else {
+            return Ok(());
+        };
+        if keys.iter().any(|share| share.nonce.len() != NONCE_LEN) {
+            self.writer.send(&ServerEvent::error("bad_encrypted_message", format!("Nonce должен занимать {} байт.", NONCE_LEN))).await?;
+            return Ok(());
+        }
+        let checked = {
+            let groups = self.server.groups.lock().await;
+            groups
+                .key_owner(&group, &self.nickname, epoch)
+                .and_then(|public_key| Ok((public_key, groups.recipients(&group, &self.nickname, epoch)?)))
+        };
+        let (public_key, members) = match checked {
+            Ok(checked) => checked,
+            Err(e) => return self.send_group_error(&group, e).await,
+        };
+        for share in keys.into_iter().filter(|share| members.contains(&share.nick)) {
+            let key = ServerEvent::GroupKey {
+                group: group.clone(),
+                from: self.nickname.clone(),
+                public_key: public_key.clone(),
+                epoch,
+                nonce: share.nonce,
+                ciphertext: share.ciphertext,
+            };
+            let _ = send_to_user(&self.server.connected_users, &share.nick, key).await;
+        }
+        log_message(LogLevel::Info, "group", &format!("'{}' раздал ключ группы {} (эпоха {})", self.nickname, group, epoch));
+        Ok(())
+    }
+
+    async fn send_group_message(&self, group: &str, epoch: u64, nonce: Vec<u8>, ciphertext: Vec<u8>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.allow(Action::Direct).await? {
+            return Ok(());
+        }
+        let Some(group) = self.group_name(group).await? else {
+            return Ok(());
+        };
+        if nonce.len() != NONCE_LEN {
+            self.writer.send(&ServerEvent::error("bad_encrypted_message", format!("Nonce должен занимать {} байт.", NONCE_LEN))).await?;
+            return Ok(());
+        }
+        let recipients = self.server.groups.lock().await.recipients(&group, &self.nickname, epoch);
+        match recipients {
+            Ok(recipients) => {
+                let message = ServerEvent::EncryptedGroupMsg { group: group.clone(), from: self.nickname.clone(), epoch, nonce, ciphertext };
+                send_to_users(&self.server.connected_users, &recipients, message).await;
+                log_user_message(LogLevel::Info, "group", &format!("'{}' отправил зашифрованное сообщение в {}", self.nickname, group), None, &[]);
+            }
+            Err(e) => self.send_group_error(&group, e).await?,
+        }
+        Ok(())
+    }
+
+    // Пропускает действие через ограничитель частоты. Если клиент превысил порог отключения,
+    // возвращает ошибку, и сессия завершается.
+    async fn allow(&self, action: Action) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let verdict = self.limiter.lock().await.check(action);
+        match verdict {
+            Verdict::Allowed => return Ok(true),
+            Verdict::Limited { retry_after } => {
+                let seconds = retry_after.as_secs_f64().ceil();
+                self.writer.send(&ServerEvent::error("rate_limited", format!("Слишком часто. Повторите через {} с.", seconds))).await?;
+            }
+            Verdict::Throttled { remaining } => {
+                let seconds = remaining.as_secs_f64().ceil();
+                self.writer.send(&ServerEvent::error("throttled", format!("Из-за флуда вы не можете писать ещё {} с. Если продолжите, вас отключат.", seconds))).await?;
+                log_message(LogLevel::Info, "rate_limit", &format!("'{}' притормаживается за флуд ({:?})", self.nickname, action));
+            }
+            Verdict::Disconnect => {
+                self.writer.send(&ServerEvent::error("flood_disconnect", "Вы отключены за флуд.")).await?;
+                log_message(LogLevel::Warn, "rate_limit", &format!("'{}' отключён за флуд", self.nickname));
+                return Err(format!("'{}' отключён за флуд", self.nickname).into());
+            }
+        }
+        Ok(false)
+    }
+
+    // Перечитывает файл пользователей и конфигурацию по команде администратора.
+    async fn cmd_reload(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.server.config().is_admin(&self.nickname) {
+            self.writer.send(&ServerEvent::error("not_admin", "Команда /reload доступна только администраторам.")).await?;
+            return Ok(());
+        }
+        log_message(LogLevel::Info, "server", &format!("'{}' запросил перезагрузку", self.nickname));
+        match self.server.reload().await {
+            Ok(report) => self.writer.send(&ServerEvent::Reloaded { report }).await?,
+            Err(e) => {
+                log_message(LogLevel::Error, "server", &format!("Ошибка перезагрузки: {}", e));
+                self.writer.send(&ServerEvent::error("reload_failed", format!("Не удалось перезагрузить: {}", e))).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Меняет уровень до перезапуска или перезагрузки конфигурации; без аргумента показывает текущий.
+    async fn cmd_log_level(&self, level: Option<&str>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.server.config().is_admin(&self.nickname) {
+            self.writer.send(&ServerEvent::error("not_admin", "Команда /loglevel доступна только администраторам.")).await?;
+            return Ok(());
+        }
+        if let Some(name) = level {
+            let Some(level) = LogLevel::parse(name) else {
+                self.writer.send(&ServerEvent::error("log_level_usage", "Использование: /loglevel [error|warn|info|debug|trace]")).await?;
+                return Ok(());
+            };
+            self.server.logger.set_level(level);
+            log_message(LogLevel::Warn, "server", &format!("'{}' сменил уровень логирования на {}", self.nickname, level.name()));
+        }
+        self.writer.send(&ServerEvent::notice("log_level", format!("Уровень логирования: {}.", self.server.logger.level().name()))).await?;
+        Ok(())
+    }
+
+    // Проверяет права администратора и цель команды модерации.
+    async fn check_moderation(&self, command: &str, nick: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        if !self.server.config().is_admin(&self.nickname) {
+            self.writer.send(&ServerEvent::error("not_admin", format!("Команда {} доступна только администраторам.", command))).await?;
+            log_message(LogLevel::Warn, "moderation", &format!("'{}' пытался выполнить {} без прав администратора", self.nickname, command));
+            return Ok(false);
+        }
+        if nick.is_empty() {
+            self.writer.send(&ServerEvent::error("moderation_usage", format!("Укажите ник: {} <ник>. Подробнее: /help", command))).await?;
+            return Ok(false);
+        }
+        if nick == self.nickname || self.server.config().is_admin(nick) {
+            self.writer.send(&ServerEvent::error("moderation_target_admin", format!("Команду {} нельзя применить к администратору.", command))).await?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    async fn cmd_kick(&self, nick: &str, reason: Option<String>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.check_moderation("/kick", nick).await? {
+            return Ok(());
+        }
+        let kicked = ServerEvent::Kicked { by: self.nickname.clone(), reason: reason.clone() };
+        if disconnect_user(&self.server.connected_users, nick, kicked).await {
+            self.writer.send(&ServerEvent::notice("user_kicked", format!("Пользователь '{}' отключён.", nick))).await?;
+            log_message(LogLevel::Info, "moderation", &format!("'{}' отключил '{}' (причина: {})", self.nickname, nick, reason.as_deref().unwrap_or("-")));
+        } else {
+            self.writer.send(&ServerEvent::error("user_offline", format!("Пользователь '{}' не найден или не в сети.", nick))).await?;
+        }
+        Ok(())
+    }
+
+    // Бан действует и на тех, кто сейчас не в сети: при следующем входе авторизация будет отклонена.
+    async fn cmd_ban(&self, nick: &str, reason: Option<String>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.check_moderation("/ban", nick).await? {
+            return Ok(());
+        }
+        let mut moderation = self.server.moderation.lock().await;
+        if !moderation.ban(nick, &self.nickname, reason.clone()) {
+            drop(moderation);
+            self.writer.send(&ServerEvent::error("already_banned", format!("Пользователь '{}' уже заблокирован.", nick))).await?;
+            return Ok(());
+        }
+        moderation.save().await?;
+        drop(moderation);
+
+        let banned = ServerEvent::Banned { by: self.nickname.clone(), reason: reason.clone() };
+        disconnect_user(&self.server.connected_users, nick, banned).await;
+        self.writer.send(&ServerEvent::notice("user_banned", format!("Пользователь '{}' заблокирован.", nick))).await?;
+        log_message(LogLevel::Info, "moderation", &format!("'{}' заблокировал '{}' (причина: {})", self.nickname, nick, reason.as_deref().unwrap_or("-")));
+        Ok(())
+    }
+
+    async fn cmd_unban(&self, nick: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.check_moderation("/unban", nick).await? {
+            return Ok(());
+        }
+        let mut moderation = self.server.moderation.lock().await;
+        if !moderation.unban(nick) {
+            drop(moderation);
+            self.writer.send(&ServerEvent::error("not_banned", format!("Пользователь '{}' не заблокирован.", nick))).await?;
+            return Ok(());
+        }
+        moderation.save().await?;
+        drop(moderation);
+        self.writer.send(&ServerEvent::notice("user_unbanned", format!("Блокировка пользователя '{}' снята.", nick))).await?;
+        log_message(LogLevel::Info, "moderation", &format!("'{}' снял блокировку с '{}'", self.nickname, nick));
+        Ok(())
+    }
+
+    async fn cmd_mute(&self, nick: &str, seconds: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.check_moderation("/mute", nick).await? {
+            return Ok(());
+        }
+        if seconds == 0 {
+            self.writer.send(&ServerEvent::error("mute_usage", "Использование: /mute <ник> <время>, например 90s, 15m, 2h или 1d.")).await?;
+            return Ok(());
+        }
+        let mut moderation = self.server.moderation.lock().await;
+        let until = format_time(moderation.mute(nick, &self.nickname, seconds));
+        moderation.save().await?;
+        drop(moderation);
+
+        let _ = send_to_user(&self.server.connected_users, nick, ServerEvent::Muted { by: self.nickname.clone(), until: until.clone() }).await;
+        self.writer.send(&ServerEvent::notice("user_muted", format!("Пользователь '{}' не сможет писать в комнаты до {}.", nick, until))).await?;
+        log_message(LogLevel::Info, "moderation", &format!("'{}' заглушил '{}' до {}", self.nickname, nick, until));
+        Ok(())
+    }
+
+    async fn cmd_unmute(&self, nick: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.check_moderation("/unmute", nick).await? {
+            return Ok(());
+        }
+        let mut moderation = self.server.moderation.lock().await;
+        if !moderation.unmute(nick) {
+            drop(moderation);
+            self.writer.send(&ServerEvent::error("not_muted", format!("Пользователь '{}' не заглушён.", nick))).await?;
+            return Ok(());
+        }
+        moderation.save().await?;
+        drop(moderation);
+
+        let _ = send_to_user(&self.server.connected_users, nick, ServerEvent::Unmuted { by: self.nickname.clone() }).await;
+        self.writer.send(&ServerEvent::notice("user_unmuted", format!("Пользователь '{}' снова может писать в комнаты.", nick))).await?;
+        log_message(LogLevel::Info, "moderation", &format!("'{}' снял заглушение с '{}'", self.nickname, nick));
+        Ok(())
+    }
+
+    // Обработка события, пришедшего через канал клиента, и вывод его в сокет.
+    async fn handle_event(&self, event: ServerEvent) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match &event {
+            ServerEvent::PrivateChatRequest { from: sender_nick, .. } => {
+                let mut state_guard = self.client_state.lock().await;
+                if !state_guard.private_chats.contains_key(sender_nick) && state_guard.incoming_requests.len() < MAX_INCOMING_REQUESTS {
+                    state_guard.private_chats.insert(sender_nick.clone(), PrivateChatStatus::PendingRequest);
+                    state_guard.incoming_requests.push_back(sender_nick.clone());
+                    drop(state_guard);
+                    self.writer.send(&event).await?;
+                    log_message(LogLevel::Info, "private_chat", &format!("'{}' получил запрос на приватный чат от '{}'", self.nickname, sender_nick));
+                } else {
+                    drop(state_guard);
+                    let _ = send_to_user(&self.server.connected_users, sender_nick, ServerEvent::PrivateChatBusy { from: self.nickname.clone() }).await;
+                    log_message(LogLevel::Info, "private_chat", &format!("'{}' получил запрос на приватный чат от '{}', но был занят.", self.nickname, sender_nick));
+                }
+            }
+            ServerEvent::PrivateChatAccepted { from: originator_nick, .. } => {
+                let mut state_guard = self.client_state.lock().await;
+                if state_guard.private_chats.get(originator_nick) == Some(&PrivateChatStatus::WaitingForResponse) {
+                    state_guard.remove(originator_nick);
+                    state_guard.private_chats.insert(originator_nick.clone(), PrivateChatStatus::Active);
+                    state_guard.focus = Some(originator_nick.clone());
+                    drop(state_guard);
+                    self.writer.send(&event).await?;
+                    self.writer.send(&ServerEvent::FocusChanged { partner: Some(originator_nick.clone()) }).await?;
+                    log_message(LogLevel::Info, "private_chat", &format!("'{}' обновил статус: приватный чат с '{}'", self.nickname, originator_nick));
+                } else {
+                    drop(state_guard);
+                    // Запрос уже отменён или истёк: собеседник не должен остаться в чате, которого нет.
+                    let _ = send_to_user(&self.server.connected_users, originator_nick, ServerEvent::PrivateChatEnded { from: self.nickname.clone() }).await;
+                    log_message(LogLevel::Warn, "private_chat", &format!("Undefined chat accept от {} для {}", originator_nick, self.nickname));
+                    self.writer.send(&ServerEvent::error("unexpected_accept", format!("Пользователь '{}' принял ваш запрос, но вы его не ожидали. Возможно, чат уже начат или отменен.", originator_nick))).await?;
+                }
+            }
+            ServerEvent::PrivateChatRejected { from: originator_nick } | ServerEvent::PrivateChatBusy { from: originator_nick } => {
+                let mut state_guard = self.client_state.lock().await;
+                if state_guard.private_chats.get(originator_nick) == Some(&PrivateChatStatus::WaitingForResponse) {
+                    state_guard.remove(originator_nick);
+                    drop(state_guard);
+                    self.writer.send(&event).await?;
+                    log_message(LogLevel::Info, "private_chat", &format!("'{}' не начал приватный чат с '{}': {:?}", originator_nick, self.nickname, event));
+                } else {
+                    drop(state_guard);
+                    log_message(LogLevel::Warn, "private_chat", &format!("Undefined chat reject/busy от {} для {}", originator_nick, self.nickname));
+                }
+            }
+            ServerEvent::PrivateChatCancelled { from: originator_nick } => {
+                let mut state_guard = self.client_state.lock().await;
+                if state_guard.private_chats.get(originator_nick) == Some(&PrivateChatStatus::PendingRequest) {
+                    state_guard.remove(originator_nick);
+                    drop(state_guard);
+                    self.writer.send(&event).await?;
+                    log_message(LogLevel::Info, "private_chat", &format!("'{}' отменил запрос на приватный чат к '{}'", originator_nick, self.nickname));
+                } else {
+                    drop(state_guard);
+                    log_message(LogLevel::Warn, "private_chat", &format!("Undefined chat cancel от {} для {}", originator_nick, self.nickname));
+                }
+            }
+            // Срок запроса истёк у отправителя: у получателя снимается входящий запрос.
+            ServerEvent::PrivateChatExpired { partner: originator_nick } => {
+                let mut state_guard = self.client_state.lock().await;
+                if state_guard.private_chats.get(originator_nick) == Some(&PrivateChatStatus::PendingRequest) {
+                    state_guard.remove(originator_nick);
+                    drop(state_guard);
+                    self.writer.send(&event).await?;
+                    log_message(LogLevel::Info, "private_chat", &format!("Запрос на приватный чат от '{}' к '{}' истёк", originator_nick, self.nickname));
+                } else {
+                    drop(state_guard);
+                    log_message(LogLevel::Debug, "private_chat", &format!("Истечение запроса от {} для {} пришло после ответа на него", originator_nick, self.nickname));
+                }
+            }
+            ServerEvent::PrivateChatEnded { from: originator_nick } => {
+                if !self.client_state.lock().await.private_chats.contains_key(originator_nick) {
+                    log_message(LogLevel::Warn, "private_chat", &format!("Undefined chat end от {} для {}", originator_nick, self.nickname));
+                    return Ok(());
+                }
+                let focus_lost = self.forget_private_chat(originator_nick).await;
+                self.writer.send(&event).await?;
+                if focus_lost {
+                    self.writer.send(&ServerEvent::FocusChanged { partner: None }).await?;
+                }
+                log_message(LogLevel::Info, "private_chat", &format!("'{}' вышел из приватного чата с '{}'", originator_nick, self.nickname));
+            }
+            ServerEvent::EncryptedPrivateMsg { from: sender_nick, .. } => {
+                if self.client_state.lock().await.is_active(sender_nick) {
+                    self.writer.send(&event).await?;
+                    log_user_message(LogLevel::Info, "private_chat", &format!("'{}' получил зашифрованное ЛС от '{}'", self.nickname, sender_nick), None, &[]);
+                } else {
+                    self.writer.send(&ServerEvent::error("not_in_private_chat", format!("Получено зашифрованное сообщение от '{}', но вы не находитесь в приватном чате с ним.", sender_nick))).await?;
+                    log_message(LogLevel::Warn, "private_chat", &format!("Получено EncryptedPrivateMsg от {} для {} в некорректном состоянии.", sender_nick, self.nickname));
+                }
+            }
+            _ => {
+                // Пока фокус на личном чате, сообщения комнаты не показываются.
+                let display_message = self.client_state.lock().await.focus.is_none() || !matches!(event, ServerEvent::ChatMessage { .. });
+                if display_message {
+                    self.writer.send(&event).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+use crate::log::{LogContent, LogFormat, LogLevel, LogRotation};
+use crate::outbound::OutboundConfig;
+use crate::ratelimit::RateLimitConfig;
+use clap::Parser;
+use serde::Deserialize;
+use std::error::Error;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_addr: String,
+    pub users_file: String,
+    pub log_file: String,
+    pub history_file: String,
+    pub inbox_file: String,
+    pub inbox_limit: usize,
+    pub moderation_file: String,
+    // Ники администраторов: им доступны /kick, /ban и /mute.
+    pub admins: Vec<String>,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    // Пишется ли в лог текст сообщений пользователей.
+    pub log_content: LogContent,
+    // Задаётся только в файле, секцией [log_rotation].
+    pub log_rotation: LogRotation,
+    pub max_clients: usize,
+    pub welcome: String,
+    pub default_room: String,
+    // Сколько секунд запрос на личный чат ждёт ответа; 0 — без ограничения.
+    pub private_request_timeout: u64,
+    // Задаётся только в файле, секцией [rate_limit].
+    pub rate_limit: RateLimitConfig,
+    // Задаётся только в файле, секцией [outbound].
+    pub outbound: OutboundConfig,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    // Аргументы, из которых собрана конфигурация: по ним она перечитывается при перезагрузке.
+    #[serde(skip)]
+    pub args: Option<CliArgs>,
+}
+
+// Что изменилось при перезагрузке конфигурации.
+#[derive(Debug, Default)]
+pub struct ConfigChanges {
+    pub applied: Vec<String>,
+    // Эти настройки получат только новые подключения: сессии остаются с прежними.
+    pub new_connections: Vec<String>,
+    // Эти настройки остаются прежними до перезапуска сервера.
+    pub restart_required: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_addr: "127.0.0.1:8080".to_string(),
+            users_file: "users.txt".to_string(),
+            log_file: "server.log".to_string(),
+            history_file: "history.jsonl".to_string(),
+            inbox_file: "inbox.json".to_string(),
+            inbox_limit: 50,
+            moderation_file: "moderation.json".to_string(),
+            admins: Vec::new(),
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+            log_content: LogContent::Metadata,
+            log_rotation: LogRotation::default(),
+            max_clients: 100,
+            welcome: "Добро пожаловать в чат! Введите /help для списка команд.".to_string(),
+            default_room: "#general".to_string(),
+            private_request_timeout: 60,
+            rate_limit: RateLimitConfig::default(),
+            outbound: OutboundConfig::default(),
+            tls_cert: None,
+            tls_key: None,
+            args: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Parser)]
+#[command(name = "kursovik", about = "Консольный чат-сервер")]
+pub struct CliArgs {
+    /// Путь к файлу конфигурации (TOML)
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Адрес для прослушивания, например 127.0.0.1:8080
+    #[arg(short, long)]
+    pub bind: Option<String>,
+    /// Файл с пользователями
+    #[arg(long)]
+    pub users_file: Option<String>,
+    /// Файл логов
+    #[arg(long)]
+    pub log_file: Option<String>,
+    /// Файл истории сообщений комнат
+    #[arg(long)]
+    pub history_file: Option<String>,
+    /// Файл с личными сообщениями для пользователей не в сети
+    #[arg(long)]
+    pub inbox_file: Option<String>,
+    /// Сколько недоставленных сообщений хранится для одного пользователя
+    #[arg(long)]
+    pub inbox_limit: Option<usize>,
+    /// Файл с банами и заглушениями
+    #[arg(long)]
+    pub moderation_file: Option<String>,
+    /// Ник администратора; флаг можно повторять. Заменяет список admins из файла
+    #[arg(long = "admin")]
+    pub admins: Vec<String>,
+    /// Уровень логирования
+    #[arg(long, value_enum)]
+    pub log_level: Option<LogLevel>,
+    /// Формат записей в файле логов
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// Что писать в лог о сообщениях пользователей: текст целиком, только метаданные или ничего
+    #[arg(long, value_enum)]
+    pub log_content: Option<LogContent>,
+    /// Максимальное число одновременных подключений
+    #[arg(long)]
+    pub max_clients: Option<usize>,
+    /// Приветственное сообщение для новых подключений
+    #[arg(long)]
+    pub welcome: Option<String>,
+    /// Комната, в которую попадают пользователи после входа
+    #[arg(long)]
+    pub default_room: Option<String>,
+    /// Сколько секунд запрос на личный чат ждёт ответа (0 — без ограничения)
+    #[arg(long)]
+    pub private_request_timeout: Option<u64>,
+    /// Сертификат TLS (PEM). Вместе с --tls-key включает TLS
+    #[arg(long)]
+    pub tls_cert: Option<String>,
+    /// Закрытый ключ TLS (PEM)
+    #[arg(long)]
+    pub tls_key: Option<String>,
+    /// Создать самоподписанный сертификат и ключ по путям --tls-cert и --tls-key и выйти
+    #[arg(long)]
+    pub generate_cert: bool,
+}
+
+impl ServerConfig {
+    pub fn is_admin(&self, nick: &str) -> bool {
+        self.admins.iter().any(|admin| admin == nick)
+    }
+
+    pub fn from_file(path: &std::path::Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Не удалось прочитать файл конфигурации {}: {}", path.display(), e))?;
+        let config = toml::from_str(&text)
+            .map_err(|e| format!("Ошибка в файле конфигурации {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    // Сначала читается файл (если указан), затем поверх применяются флаги командной строки.
+    pub fn from_args(args: &CliArgs) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut config = match &args.config {
+            Some(path) => ServerConfig::from_file(path)?,
+            None => ServerConfig::default(),
+        };
+        if let Some(bind) = &args.bind {
+            config.bind_addr = bind.clone();
+        }
+        if let Some(users_file) = &args.users_file {
+            config.users_file = users_file.clone();
+        }
+        if let Some(log_file) = &args.log_file {
+            config.log_file = log_file.clone();
+        }
+        if let Some(history_file) = &args.history_file {
+            config.history_file = history_file.clone();
+        }
+        if let Some(inbox_file) = &args.inbox_file {
+            config.inbox_file = inbox_file.clone();
+        }
+        if let Some(inbox_limit) = args.inbox_limit {
+            config.inbox_limit = inbox_limit;
+        }
+        if let Some(moderation_file) = &args.moderation_file {
+            config.moderation_file = moderation_file.clone();
+        }
+        if !args.admins.is_empty() {
+            config.admins = args.admins.clone();
+        }
+        if let Some(log_level) = args.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(log_format) = args.log_format {
+            config.log_format = log_format;
+        }
+        if let Some(log_content) = args.log_content {
+            config.log_content = log_content;
+        }
+        if let Some(max_clients) = args.max_clients {
+            config.max_clients = max_clients;
+        }
+        if let Some(welcome) = &args.welcome {
+            config.welcome = welcome.clone();
+        }
+        if let Some(default_room) = &args.default_room {
+            config.default_room = default_room.clone();
+        }
+        if let Some(private_request_timeout) = args.private_request_timeout {
+            config.private_request_timeout = private_request_timeout;
+        }
+        if let Some(tls_cert) = &args.tls_cert {
+            config.tls_cert = Some(tls_cert.clone());
+        }
+        if let Some(tls_key) = &args.tls_key {
+            config.tls_key = Some(tls_key.clone());
+        }
+        config.args = Some(args.clone());
+        Ok(config)
+    }
+
+    // Заново читает файл и флаги, из которых собрана конфигурация. Собранную в коде конфигурацию перечитывать неоткуда.
+    pub fn reread(&self) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        match &self.args {
+            Some(args) => ServerConfig::from_args(args),
+            None => Ok(self.clone()),
+        }
+    }
+
+    // Переносит в новую конфигурацию настройки, которые нельзя поменять на ходу, и перечисляет изменения.
+    pub fn merge_reloaded(&self, mut new: ServerConfig) -> (ServerConfig, ConfigChanges) {
+        let mut changes = ConfigChanges::default();
+        macro_rules! applied {
+            ($($field:ident),*) => {$(
+                if self.$field != new.$field {
+                    changes.applied.push(stringify!($field).to_string());
+                }
+            )*};
+        }
+        macro_rules! new_connections {
+            ($($field:ident),*) => {$(
+                if self.$field != new.$field {
+                    changes.new_connections.push(stringify!($field).to_string());
+                }
+            )*};
+        }
+        macro_rules! restart_required {
+            ($($field:ident),*) => {$(
+                if self.$field != new.$field {
+                    changes.restart_required.push(stringify!($field).to_string());
+                    new.$field = self.$field.clone();
+                }
+            )*};
+        }
+        applied!(admins, log_level, log_format, log_content, log_rotation, max_clients, welcome, private_request_timeout, inbox_limit);
+        new_connections!(rate_limit, outbound);
+        restart_required!(bind_addr, users_file, log_file, history_file, inbox_file, moderation_file, default_room, tls_cert, tls_key);
+        (new, changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_reloaded_sorts_changes_by_when_they_take_effect() {
+        let old = ServerConfig::default();
+        let mut reread = ServerConfig { inbox_limit: old.inbox_limit + 1, bind_addr: "0.0.0.0:9000".to_string(), ..ServerConfig::default() };
+        reread.rate_limit.burst += 1;
+        reread.outbound.queue_size += 1;
+
+        let (merged, changes) = old.merge_reloaded(reread);
+        assert_eq!(changes.applied, ["inbox_limit"]);
+        assert_eq!(changes.new_connections, ["rate_limit", "outbound"]);
+        assert_eq!(changes.restart_required, ["bind_addr"]);
+        assert_eq!(merged.bind_addr, old.bind_addr);
+        assert_eq!(merged.inbox_limit, old.inbox_limit + 1);
+        assert_eq!(merged.rate_limit.burst, old.rate_limit.burst + 1);
+    }
+}
@@ -0,0 +1,277 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+// Сквозное шифрование личных чатов и групп. Выполняется только на стороне клиентов:
+// сервер пересылает открытые ключи X25519 и шифртекст, но не может их прочитать.
+// Ключ группы случайный; владелец передаёт его каждому участнику, зашифровав общим ключом X25519.
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+
+const KDF_INFO: &[u8] = b"kursovik private chat v1";
+const GROUP_KDF_INFO: &[u8] = b"kursovik group key v1";
+const KEY_LEN: usize = 32;
+
+// Ключевой материал: затирается в памяти при освобождении, не клонируется
+// и ни через {:?}, ни через {} не выводится.
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    // Позволяет заполнить секрет на месте, не оставляя его копий в памяти.
+    pub fn expose_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret([скрыто])")
+    }
+}
+
+impl<T: Zeroize> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[скрыто]")
+    }
+}
+
+// Секретная часть затирается самой x25519-dalek при освобождении.
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair").field("public", &hex::encode(self.public.as_bytes())).field("secret", &"[скрыто]").finish()
+    }
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        KeyPair { secret, public }
+    }
+
+    pub fn public_bytes(&self) -> Vec<u8> {
+        self.public.as_bytes().to_vec()
+    }
+
+    // Общий ключ AES-256 из своего секрета и открытого ключа собеседника.
+    pub fn derive_session_key(&self, peer_public: &[u8]) -> Result<SessionKey, String> {
+        self.derive(peer_public, KDF_INFO)
+    }
+
+    // Ключ, которым владелец группы шифрует для участника ключ группы.
+    pub fn derive_wrapping_key(&self, peer_public: &[u8]) -> Result<SessionKey, String> {
+        self.derive(peer_public, GROUP_KDF_INFO)
+    }
+
+    fn derive(&self, peer_public: &[u8], info: &[u8]) -> Result<SessionKey, String> {
+        let peer_bytes: [u8; PUBLIC_KEY_LEN] = peer_public
+            .try_into()
+            .map_err(|_| format!("Открытый ключ должен занимать {} байта", PUBLIC_KEY_LEN))?;
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer_bytes));
+        if !shared.was_contributory() {
+            return Err("Некорректный открытый ключ собеседника".to_string());
+        }
+
+        let mut key = Secret::new([0u8; KEY_LEN]);
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(info, key.expose_mut())
+            .map_err(|e| format!("Ошибка вывода ключа: {}", e))?;
+        Ok(SessionKey { key })
+    }
+}
+
+#[derive(Debug)]
+pub struct SessionKey {
+    key: Secret<[u8; KEY_LEN]>,
+}
+
+impl SessionKey {
+    // Случайный ключ группы.
+    pub fn generate() -> Self {
+        let mut key = Secret::new([0u8; KEY_LEN]);
+        OsRng.fill_bytes(key.expose_mut());
+        SessionKey { key }
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Result<(Vec<u8>, Vec<u8>), String> {
+        self.seal(plaintext.as_bytes())
+    }
+
+    pub fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<String, String> {
+        let plaintext = self.open(nonce, ciphertext)?;
+        String::from_utf8(plaintext).map_err(|_| "Расшифрованное сообщение не является UTF-8".to_string())
+    }
+
+    // Шифрует другой ключ этим: так владелец передаёт участнику ключ группы.
+    pub fn wrap(&self, key: &SessionKey) -> Result<(Vec<u8>, Vec<u8>), String> {
+        self.seal(key.key.expose())
+    }
+
+    pub fn unwrap(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<SessionKey, String> {
+        let plaintext = Secret::new(self.open(nonce, ciphertext)?);
+        if plaintext.expose().len() != KEY_LEN {
+            return Err(format!("Ключ группы должен занимать {} байта", KEY_LEN));
+        }
+        let mut key = Secret::new([0u8; KEY_LEN]);
+        key.expose_mut().copy_from_slice(plaintext.expose());
+        Ok(SessionKey { key })
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let cipher = Aes256Gcm::new_from_slice(self.key.expose()).expect("Key length is 32 bytes");
+        let mut nonce_array = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce_array);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce_array), plaintext)
+            .map_err(|e| format!("Ошибка шифрования: {:?}", e))?;
+        Ok((nonce_array.to_vec(), ciphertext))
+    }
+
+    fn open(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+        if nonce.len() != NONCE_LEN {
+            return Err("Неверная длина nonce".to_string());
+        }
+        let cipher = Aes256Gcm::new_from_slice(self.key.expose()).expect("Key length is 32 bytes");
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Ошибка дешифрования сообщения. Возможно, ключ неверный.".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sides_derive_the_same_session_key() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let alice_key = alice.derive_session_key(&bob.public_bytes()).unwrap();
+        let bob_key = bob.derive_session_key(&alice.public_bytes()).unwrap();
+        assert_eq!(alice_key.key.expose(), bob_key.key.expose());
+
+        let eve = KeyPair::generate();
+        let eve_key = eve.derive_session_key(&bob.public_bytes()).unwrap();
+        assert_ne!(alice_key.key.expose(), eve_key.key.expose());
+    }
+
+    #[test]
+    fn message_survives_encrypt_decrypt_round_trip() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let alice_key = alice.derive_session_key(&bob.public_bytes()).unwrap();
+        let bob_key = bob.derive_session_key(&alice.public_bytes()).unwrap();
+
+        let (nonce, ciphertext) = alice_key.encrypt("привет, Боб").unwrap();
+        assert_eq!(nonce.len(), NONCE_LEN);
+        assert_ne!(ciphertext, "привет, Боб".as_bytes());
+        assert_eq!(bob_key.decrypt(&nonce, &ciphertext).unwrap(), "привет, Боб");
+    }
+
+    #[test]
+    fn tampered_ciphertext_or_nonce_is_rejected() {
+        let key = SessionKey::generate();
+        let (nonce, ciphertext) = key.encrypt("перевести 100").unwrap();
+
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert!(key.decrypt(&nonce, &tampered).is_err());
+
+        let mut other_nonce = nonce.clone();
+        other_nonce[0] ^= 1;
+        assert!(key.decrypt(&other_nonce, &ciphertext).is_err());
+        assert!(key.decrypt(&nonce[1..], &ciphertext).is_err());
+        assert!(key.decrypt(&nonce, &ciphertext[..ciphertext.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn non_contributory_or_malformed_peer_key_is_rejected() {
+        let alice = KeyPair::generate();
+        assert!(alice.derive_session_key(&[0u8; PUBLIC_KEY_LEN]).is_err());
+        assert!(alice.derive_wrapping_key(&[0u8; PUBLIC_KEY_LEN]).is_err());
+        assert!(alice.derive_session_key(&[1u8; PUBLIC_KEY_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn group_key_survives_wrap_unwrap_round_trip() {
+        let owner = KeyPair::generate();
+        let member = KeyPair::generate();
+        let owner_wrapping = owner.derive_wrapping_key(&member.public_bytes()).unwrap();
+        let member_wrapping = member.derive_wrapping_key(&owner.public_bytes()).unwrap();
+        // Ключ для раздачи ключа группы отличается от ключа личного чата той же пары.
+        assert_ne!(owner_wrapping.key.expose(), owner.derive_session_key(&member.public_bytes()).unwrap().key.expose());
+
+        let group_key = SessionKey::generate();
+        let (nonce, wrapped) = owner_wrapping.wrap(&group_key).unwrap();
+        let unwrapped = member_wrapping.unwrap(&nonce, &wrapped).unwrap();
+        assert_eq!(unwrapped.key.expose(), group_key.key.expose());
+
+        let (nonce, ciphertext) = group_key.encrypt("всем привет").unwrap();
+        assert_eq!(unwrapped.decrypt(&nonce, &ciphertext).unwrap(), "всем привет");
+    }
+
+    #[test]
+    fn group_key_does_not_unwrap_with_wrong_session_key() {
+        let owner = KeyPair::generate();
+        let member = KeyPair::generate();
+        let outsider = KeyPair::generate();
+        let group_key = SessionKey::generate();
+        let (nonce, wrapped) = owner.derive_wrapping_key(&member.public_bytes()).unwrap().wrap(&group_key).unwrap();
+
+        let outsider_wrapping = outsider.derive_wrapping_key(&owner.public_bytes()).unwrap();
+        assert!(outsider_wrapping.unwrap(&nonce, &wrapped).is_err());
+        // Ключ личного чата той же пары тоже не подходит.
+        let member_session = member.derive_session_key(&owner.public_bytes()).unwrap();
+        assert!(member_session.unwrap(&nonce, &wrapped).is_err());
+    }
+
+    // Ни одно представление не должно содержать байты ключа ни в hex, ни в виде массива.
+    fn assert_hides(rendered: &str, key: &[u8]) {
+        assert!(!rendered.contains(&hex::encode(key)), "{}", rendered);
+        assert!(!rendered.contains(&format!("{:?}", key)), "{}", rendered);
+        assert!(!rendered.contains(&format!("{:?}", key).replace(", ", ",")), "{}", rendered);
+    }
+
+    #[test]
+    fn formatting_never_reveals_key_material() {
+        let secret = Secret::new([0x5au8; KEY_LEN]);
+        assert_hides(&format!("{:?}", secret), secret.expose());
+        assert_hides(&format!("{:#?}", secret), secret.expose());
+        assert_hides(&format!("{}", secret), secret.expose());
+
+        let session_key = SessionKey::generate();
+        assert_hides(&format!("{:?}", session_key), session_key.key.expose());
+        assert_hides(&format!("{:#?}", session_key), session_key.key.expose());
+        assert_hides(&format!("{}", session_key.key), session_key.key.expose());
+
+        let pair = KeyPair::generate();
+        assert_hides(&format!("{:?}", pair), &pair.secret.to_bytes());
+        assert_hides(&format!("{:#?}", pair), &pair.secret.to_bytes());
+        assert!(format!("{:?}", pair).contains(&hex::encode(pair.public_bytes())));
+    }
+}
@@ -0,0 +1,380 @@
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+// События, которые передаются через канал каждого клиента и ответы на команды.
+// В текст или JSON они превращаются только при записи в сокет.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Welcome { text: String },
+    ModeChanged { protocol: String },
+    Prompt { field: PromptField },
+    AuthSuccess { nick: String, registered: bool },
+    UserList { users: Vec<String> },
+    Help { commands: Vec<HelpEntry> },
+    ChatMessage { room: String, from: String, text: String },
+    DirectMessage { from: String, text: String },
+    OfflineMessages { messages: Vec<OfflineMessage> },
+    UserJoined { nick: String, room: String },
+    UserLeft { nick: String, room: String },
+    RoomJoined { room: String, users: Vec<String> },
+    RoomList { rooms: Vec<RoomInfo> },
+    RoomMembers { room: String, users: Vec<String> },
+    History { room: String, entries: Vec<HistoryEntry> },
+    PrivateChatRequest {
+        from: String,
+        #[serde(with = "hex::serde")]
+        public_key: Vec<u8>,
+    },
+    PrivateChatAccepted {
+        from: String,
+        #[serde(with = "hex::serde")]
+        public_key: Vec<u8>,
+    },
+    PrivateChatRejected { from: String },
+    PrivateChatEnded { from: String },
+    PrivateChatBusy { from: String },
+    PrivateChatCancelled { from: String },
+    // Запрос между вами и partner остался без ответа и снят по таймауту.
+    PrivateChatExpired { partner: String },
+    FocusChanged { partner: Option<String> },
+    PrivateChatRequests { requests: Vec<String> },
+    PrivateChats { chats: Vec<PrivateChatInfo>, focus: Option<String> },
+    EncryptedPrivateMsg {
+        from: String,
+        #[serde(with = "hex::serde")]
+        nonce: Vec<u8>,
+        #[serde(with = "hex::serde")]
+        ciphertext: Vec<u8>,
+    },
+    GroupInvite { group: String, from: String },
+    GroupInviteDeclined { group: String, from: String },
+    // Новый состав группы; владелец должен раздать ключ для новой эпохи.
+    GroupUpdated { group: GroupInfo },
+    Groups { groups: Vec<GroupInfo> },
+    // Ключ группы, зашифрованный общим ключом владельца и получателя.
+    GroupKey {
+        group: String,
+        from: String,
+        #[serde(with = "hex::serde")]
+        public_key: Vec<u8>,
+        epoch: u64,
+        #[serde(with = "hex::serde")]
+        nonce: Vec<u8>,
+        #[serde(with = "hex::serde")]
+        ciphertext: Vec<u8>,
+    },
+    EncryptedGroupMsg {
+        group: String,
+        from: String,
+        epoch: u64,
+        #[serde(with = "hex::serde")]
+        nonce: Vec<u8>,
+        #[serde(with = "hex::serde")]
+        ciphertext: Vec<u8>,
+    },
+    Kicked { by: String, reason: Option<String> },
+    Banned { by: String, reason: Option<String> },
+    Muted { by: String, until: String },
+    Unmuted { by: String },
+    Reloaded { report: ReloadReport },
+    SystemNotice { code: String, text: String },
+    Error { code: String, text: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptField {
+    Nick,
+    Password,
+    Register,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HelpEntry {
+    pub command: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub timestamp: String,
+    pub room: String,
+    pub from: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfflineMessage {
+    pub timestamp: String,
+    pub from: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivateChatStatus {
+    // Мы отправили запрос и ждём ответа.
+    WaitingForResponse,
+    // Собеседник прислал запрос, ждём /accept или /reject.
+    PendingRequest,
+    Active,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrivateChatInfo {
+    pub nick: String,
+    pub status: PrivateChatStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMember {
+    pub nick: String,
+    #[serde(with = "hex::serde")]
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupInfo {
+    pub name: String,
+    pub owner: String,
+    pub epoch: u64,
+    pub members: Vec<GroupMember>,
+}
+
+impl GroupInfo {
+    fn render(&self) -> String {
+        let members: Vec<&str> = self.members.iter().map(|member| member.nick.as_str()).collect();
+        format!("{} (владелец {}, эпоха ключа {}): {}", self.name.blue(), self.owner, self.epoch, members.join(", "))
+    }
+}
+
+// Итог перезагрузки: изменения учётных записей и настроек.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReloadReport {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    // Учётные записи, у которых сменился пароль.
+    pub changed: Vec<String>,
+    pub settings: Vec<String>,
+    // Изменённые настройки, которые действуют только для новых подключений.
+    pub new_connections: Vec<String>,
+    // Изменённые настройки, которые вступят в силу только после перезапуска.
+    pub restart_required: Vec<String>,
+}
+
+impl ReloadReport {
+    pub fn summary(&self) -> String {
+        let list = |items: &[String]| if items.is_empty() { "нет".to_string() } else { items.join(", ") };
+        let mut text = format!(
+            "добавлены: {}; удалены: {}; изменены: {}; настройки: {}",
+            list(&self.added),
+            list(&self.removed),
+            list(&self.changed),
+            list(&self.settings)
+        );
+        if !self.new_connections.is_empty() {
+            text.push_str(&format!("; для новых подключений: {}", self.new_connections.join(", ")));
+        }
+        if !self.restart_required.is_empty() {
+            text.push_str(&format!("; требуют перезапуска: {}", self.restart_required.join(", ")));
+        }
+        text
+    }
+}
+
+fn render_reason(reason: &Option<String>) -> String {
+    match reason {
+        Some(reason) => format!(". Причина: {}", reason),
+        None => ".".to_string(),
+    }
+}
+
+impl ServerEvent {
+    // Значение поля type в JSON, например "chat_message".
+    pub fn kind(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|value| value.get("type").and_then(|kind| kind.as_str()).map(str::to_string))
+            .unwrap_or_default()
+    }
+
+    // Несёт ли событие то, что написали пользователи (в том числе в зашифрованном виде).
+    pub fn has_user_content(&self) -> bool {
+        matches!(
+            self,
+            ServerEvent::ChatMessage { .. }
+                | ServerEvent::DirectMessage { .. }
+                | ServerEvent::OfflineMessages { .. }
+                | ServerEvent::History { .. }
+                | ServerEvent::EncryptedPrivateMsg { .. }
+                | ServerEvent::GroupKey { .. }
+                | ServerEvent::EncryptedGroupMsg { .. }
+        )
+    }
+
+    pub fn notice(code: &str, text: impl Into<String>) -> Self {
+        ServerEvent::SystemNotice { code: code.to_string(), text: text.into() }
+    }
+
+    pub fn error(code: &str, text: impl Into<String>) -> Self {
+        ServerEvent::Error { code: code.to_string(), text: text.into() }
+    }
+
+    pub fn server_shutdown() -> Self {
+        ServerEvent::notice("server_shutdown", "Сервер останавливается. Соединение будет закрыто.")
+    }
+
+    pub fn render_text(&self) -> String {
+        match self {
+            ServerEvent::Welcome { text } => format!("{}\n", text),
+            ServerEvent::ModeChanged { protocol } => format!("Режим протокола: {}\n", protocol),
+            ServerEvent::Prompt { field: PromptField::Nick } => "Введите никнейм:\n".to_string(),
+            ServerEvent::Prompt { field: PromptField::Password } => "Введите пароль:\n".to_string(),
+            ServerEvent::Prompt { field: PromptField::Register } => {
+                "Пользователь не найден. Хотите зарегистрироваться? (да/нет):\n".to_string()
+            }
+            ServerEvent::AuthSuccess { registered: false, .. } => "Авторизация успешна!\n".to_string(),
+            ServerEvent::AuthSuccess { registered: true, .. } => "Регистрация успешна! Вы авторизованы.\n".to_string(),
+            ServerEvent::UserList { users } if users.is_empty() => "Пока никто больше не подключён.\n".to_string(),
+            ServerEvent::UserList { users } => format!("Сейчас в сети: {}\n", users.join(", ")),
+            ServerEvent::Help { commands } => {
+                let mut text = "Доступные команды:\n".to_string();
+                for entry in commands {
+                    text.push_str(&format!("\t{} - {}\n", entry.command, entry.description));
+                }
+                text
+            }
+            ServerEvent::ChatMessage { room, from, text } => format!("{} {}: {}\n", room.blue(), from, text),
+            ServerEvent::DirectMessage { from, text } => format!("{} {}: {}\n", "Вам".cyan(), from, text),
+            ServerEvent::OfflineMessages { messages } => {
+                let mut text = "Сообщения, пришедшие, пока вас не было:\n".to_string();
+                for message in messages {
+                    text.push_str(&format!("[{}] {} {}: {}\n", message.timestamp, "Вам".cyan(), message.from, message.text));
+                }
+                text
+            }
+            ServerEvent::UserJoined { nick, room } => format!("Пользователь '{}' вошёл в комнату {}\n", nick, room),
+            ServerEvent::UserLeft { nick, room } => format!("Пользователь '{}' вышел из комнаты {}\n", nick, room),
+            ServerEvent::RoomJoined { room, users } if users.is_empty() => {
+                format!("Вы в комнате {}. Кроме вас здесь никого нет.\n", room.blue())
+            }
+            ServerEvent::RoomJoined { room, users } => {
+                format!("Вы в комнате {}. Здесь также: {}\n", room.blue(), users.join(", "))
+            }
+            ServerEvent::RoomList { rooms } => {
+                let rooms: Vec<String> = rooms.iter().map(|info| format!("{} ({})", info.name, info.members)).collect();
+                format!("Комнаты: {}\n", rooms.join(", "))
+            }
+            ServerEvent::RoomMembers { room, users } if users.is_empty() => format!("Комната {} пуста.\n", room),
+            ServerEvent::RoomMembers { room, users } => format!("В комнате {}: {}\n", room, users.join(", ")),
+            ServerEvent::History { room, entries } if entries.is_empty() => format!("История комнаты {} пуста.\n", room),
+            ServerEvent::History { room, entries } => {
+                let mut text = format!("Последние сообщения в {}:\n", room.blue());
+                for entry in entries {
+                    text.push_str(&format!("[{}] {}: {}\n", entry.timestamp, entry.from, entry.text));
+                }
+                text
+            }
+            ServerEvent::PrivateChatRequest { from, .. } => {
+                format!("Пользователь '{}' хочет начать с вами личный чат. Введите /accept {} или /reject {}.\n", from, from, from)
+            }
+            ServerEvent::PrivateChatAccepted { from, .. } => {
+                format!("{} Пользователь '{}' принял ваш запрос на личный чат.\n", "ИНФО:".green(), from)
+            }
+            ServerEvent::PrivateChatRejected { from } => {
+                format!("{} Пользователь '{}' отклонил ваш запрос на личный чат.\n", "ИНФО:".green(), from)
+            }
+            ServerEvent::PrivateChatEnded { from } => {
+                format!("{} Пользователь '{}' вышел из личного чата.\n", "ИНФО:".green(), from)
+            }
+            ServerEvent::PrivateChatBusy { from } => {
+                format!("{} Пользователь '{}' занят и не может принять ваш запрос.\n", "ИНФО:".green(), from)
+            }
+            ServerEvent::PrivateChatCancelled { from } => {
+                format!("{} Пользователь '{}' отменил запрос на личный чат.\n", "ИНФО:".green(), from)
+            }
+            ServerEvent::PrivateChatExpired { partner } => {
+                format!("{} Запрос на личный чат с '{}' остался без ответа и отменён.\n", "ИНФО:".green(), partner)
+            }
+            ServerEvent::FocusChanged { partner: Some(partner) } => {
+                format!("Ввод переключён на личный чат с '{}'. /switch — вернуться в комнату.\n", partner.cyan())
+            }
+            ServerEvent::FocusChanged { partner: None } => "Ввод переключён на текущую комнату.\n".to_string(),
+            ServerEvent::PrivateChatRequests { requests } if requests.is_empty() => "Входящих запросов на личный чат нет.\n".to_string(),
+            ServerEvent::PrivateChatRequests { requests } => {
+                let mut text = "Входящие запросы на личный чат (первый принимается /accept без ника):\n".to_string();
+                for (i, nick) in requests.iter().enumerate() {
+                    text.push_str(&format!("\t{}. {}\n", i + 1, nick));
+                }
+                text
+            }
+            ServerEvent::PrivateChats { chats, .. } if chats.is_empty() => "У вас нет личных чатов.\n".to_string(),
+            ServerEvent::PrivateChats { chats, focus } => {
+                let mut text = "Личные чаты:\n".to_string();
+                for chat in chats {
+                    let status = match chat.status {
+                        PrivateChatStatus::WaitingForResponse => "ожидает ответа",
+                        PrivateChatStatus::PendingRequest => "ждёт вашего /accept или /reject",
+                        PrivateChatStatus::Active => "активен",
+                    };
+                    let marker = if focus.as_deref() == Some(chat.nick.as_str()) { "*" } else { " " };
+                    text.push_str(&format!("\t{} {} - {}\n", marker, chat.nick, status));
+                }
+                text
+            }
+            ServerEvent::EncryptedPrivateMsg { from, nonce, ciphertext } => {
+                format!("[Зашифрованное ЛС от {}]: {} {}\n", from.cyan(), hex::encode(nonce), hex::encode(ciphertext))
+            }
+            ServerEvent::GroupInvite { group, from } => {
+                format!("Пользователь '{}' приглашает вас в группу {}. Введите /group accept {} или /group decline {}.\n", from, group.blue(), group, group)
+            }
+            ServerEvent::GroupInviteDeclined { group, from } => {
+                format!("{} Пользователь '{}' отклонил приглашение в группу {}.\n", "ИНФО:".green(), from, group)
+            }
+            ServerEvent::GroupUpdated { group } => format!("Состав группы изменился: {}\n", group.render()),
+            ServerEvent::Groups { groups } if groups.is_empty() => "Вы не состоите ни в одной группе.\n".to_string(),
+            ServerEvent::Groups { groups } => {
+                let mut text = "Ваши группы:\n".to_string();
+                for group in groups {
+                    text.push_str(&format!("\t{}\n", group.render()));
+                }
+                text
+            }
+            ServerEvent::GroupKey { group, from, public_key, epoch, nonce, ciphertext } => {
+                format!("[Ключ группы {} (эпоха {}) от {}]: {} {} {}\n", group.blue(), epoch, from.cyan(), hex::encode(public_key), hex::encode(nonce), hex::encode(ciphertext))
+            }
+            ServerEvent::EncryptedGroupMsg { group, from, epoch, nonce, ciphertext } => {
+                format!("[Зашифрованное сообщение в {} (эпоха {}) от {}]: {} {}\n", group.blue(), epoch, from.cyan(), hex::encode(nonce), hex::encode(ciphertext))
+            }
+            ServerEvent::Kicked { by, reason } => {
+                format!("{} Администратор '{}' отключил вас от сервера{}\n", "ВНИМАНИЕ:".red(), by, render_reason(reason))
+            }
+            ServerEvent::Banned { by, reason } => {
+                format!("{} Администратор '{}' заблокировал вас на сервере{}\n", "ВНИМАНИЕ:".red(), by, render_reason(reason))
+            }
+            ServerEvent::Muted { by, until } => {
+                format!("{} Администратор '{}' запретил вам писать в комнаты до {}.\n", "ВНИМАНИЕ:".red(), by, until)
+            }
+            ServerEvent::Unmuted { by } => {
+                format!("{} Администратор '{}' снова разрешил вам писать в комнаты.\n", "ИНФО:".green(), by)
+            }
+            ServerEvent::Reloaded { report } => {
+                format!("{} Файл пользователей и конфигурация перечитаны: {}.\n", "ИНФО:".green(), report.summary())
+            }
+            ServerEvent::SystemNotice { text, .. } => format!("{}\n", text),
+            ServerEvent::Error { text, .. } => format!("{}\n", text),
+        }
+    }
+
+    pub fn render_json(&self) -> String {
+        let json = serde_json::to_string(self).expect("ServerEvent всегда сериализуется в JSON");
+        format!("{}\n", json)
+    }
+}
@@ -0,0 +1,282 @@
+use crate::event::{GroupInfo, GroupMember};
+use crate::rooms::normalize_name;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Приводит имя к виду "&имя".
+pub fn normalize_group_name(name: &str) -> Option<String> {
+    normalize_name('&', name)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupError {
+    NotFound,
+    Exists,
+    NotMember,
+    NotOwner,
+    AlreadyMember,
+    NotInvited,
+    StaleKey,
+}
+
+impl GroupError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            GroupError::NotFound => "group_not_found",
+            GroupError::Exists => "group_exists",
+            GroupError::NotMember => "not_group_member",
+            GroupError::NotOwner => "not_group_owner",
+            GroupError::AlreadyMember => "already_group_member",
+            GroupError::NotInvited => "not_invited",
+            GroupError::StaleKey => "stale_group_key",
+        }
+    }
+
+    pub fn text(&self, group: &str) -> String {
+        match self {
+            GroupError::NotFound => format!("Группы {} нет.", group),
+            GroupError::Exists => format!("Группа {} уже существует.", group),
+            GroupError::NotMember => format!("Вы не состоите в группе {}.", group),
+            GroupError::NotOwner => format!("Это может сделать только владелец группы {}.", group),
+            GroupError::AlreadyMember => format!("Пользователь уже состоит в группе {} или приглашён в неё.", group),
+            GroupError::NotInvited => format!("Вас не приглашали в группу {}.", group),
+            GroupError::StaleKey => format!("Ключ группы {} обновляется, повторите через несколько секунд.", group),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Group {
+    owner: String,
+    epoch: u64,
+    // Участники и их открытые ключи X25519, которыми владелец шифрует для них ключ группы.
+    members: BTreeMap<String, Vec<u8>>,
+    invited: BTreeSet<String>,
+}
+
+impl Group {
+    fn info(&self, name: &str) -> GroupInfo {
+        GroupInfo {
+            name: name.to_string(),
+            owner: self.owner.clone(),
+            epoch: self.epoch,
+            members: self
+                .members
+                .iter()
+                .map(|(nick, public_key)| GroupMember { nick: nick.clone(), public_key: public_key.clone() })
+                .collect(),
+        }
+    }
+}
+
+// Закрытые группы с общим ключом. Сервер знает только состав и номер эпохи ключа:
+// каждое изменение состава начинает новую эпоху, и владелец раздаёт участникам новый ключ.
+#[derive(Debug, Default)]
+pub struct GroupRegistry {
+    groups: BTreeMap<String, Group>,
+}
+
+impl GroupRegistry {
+    pub fn create(&mut self, name: &str, owner: &str, public_key: Vec<u8>) -> Result<GroupInfo, GroupError> {
+        if self.groups.contains_key(name) {
+            return Err(GroupError::Exists);
+        }
+        let group = Group {
+            owner: owner.to_string(),
+            epoch: 1,
+            members: BTreeMap::from([(owner.to_string(), public_key)]),
+            invited: BTreeSet::new(),
+        };
+        let info = group.info(name);
+        self.groups.insert(name.to_string(), group);
+        Ok(info)
+    }
+
+    pub fn invite(&mut self, name: &str, by: &str, nick: &str) -> Result<(), GroupError> {
+        let group = self.groups.get_mut(name).ok_or(GroupError::NotFound)?;
+        if group.owner != by {
+            return Err(if group.members.contains_key(by) { GroupError::NotOwner } else { GroupError::NotMember });
+        }
+        if group.members.contains_key(nick) || !group.invited.insert(nick.to_string()) {
+            return Err(GroupError::AlreadyMember);
+        }
+        Ok(())
+    }
+
+    pub fn accept(&mut self, name: &str, nick: &str, public_key: Vec<u8>) -> Result<GroupInfo, GroupError> {
+        let group = self.groups.get_mut(name).ok_or(GroupError::NotFound)?;
+        if !group.invited.remove(nick) {
+            return Err(GroupError::NotInvited);
+        }
+        group.members.insert(nick.to_string(), public_key);
+        group.epoch += 1;
+        Ok(group.info(name))
+    }
+
+    // Снимает приглашение и возвращает владельца группы, чтобы его уведомить.
+    pub fn decline(&mut self, name: &str, nick: &str) -> Result<String, GroupError> {
+        let group = self.groups.get_mut(name).ok_or(GroupError::NotFound)?;
+        if !group.invited.remove(nick) {
+            return Err(GroupError::NotInvited);
+        }
+        Ok(group.owner.clone())
+    }
+
+    // Возвращает новый состав группы или None, если ушёл последний участник и группа удалена.
+    // Если уходит владелец, владельцем становится первый по алфавиту из оставшихся.
+    pub fn leave(&mut self, name: &str, nick: &str) -> Result<Option<GroupInfo>, GroupError> {
+        let group = self.groups.get_mut(name).ok_or(GroupError::NotFound)?;
+        if group.members.remove(nick).is_none() {
+            return Err(GroupError::NotMember);
+        }
+        let Some(next_owner) = group.members.keys().next().cloned() else {
+            self.groups.remove(name);
+            return Ok(None);
+        };
+        if group.owner == nick {
+            group.owner = next_owner;
+        }
+        group.epoch += 1;
+        Ok(Some(group.info(name)))
+    }
+
+    // Выход из всех групп при отключении; возвращает изменившиеся группы, которые ещё существуют.
+    pub fn remove_user(&mut self, nick: &str) -> Vec<GroupInfo> {
+        for group in self.groups.values_mut() {
+            group.invited.remove(nick);
+        }
+        let names: Vec<String> = self
+            .groups
+            .iter()
+            .filter(|(_, group)| group.members.contains_key(nick))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.iter().filter_map(|name| self.leave(name, nick).ok().flatten()).collect()
+    }
+
+    pub fn groups_of(&self, nick: &str) -> Vec<GroupInfo> {
+        self.groups
+            .iter()
+            .filter(|(_, group)| group.members.contains_key(nick))
+            .map(|(name, group)| group.info(name))
+            .collect()
+    }
+
+    // Проверяет, что from состоит в группе и пишет ключом текущей эпохи, и возвращает остальных участников.
+    pub fn recipients(&self, name: &str, from: &str, epoch: u64) -> Result<Vec<String>, GroupError> {
+        let group = self.groups.get(name).ok_or(GroupError::NotFound)?;
+        if !group.members.contains_key(from) {
+            return Err(GroupError::NotMember);
+        }
+        if group.epoch != epoch {
+            return Err(GroupError::StaleKey);
+        }
+        Ok(group.members.keys().filter(|nick| *nick != from).cloned().collect())
+    }
+
+    // Раздавать ключ может только владелец и только для текущей эпохи; возвращает его открытый ключ.
+    pub fn key_owner(&self, name: &str, from: &str, epoch: u64) -> Result<Vec<u8>, GroupError> {
+        let group = self.groups.get(name).ok_or(GroupError::NotFound)?;
+        if group.owner != from {
+            return Err(if group.members.contains_key(from) { GroupError::NotOwner } else { GroupError::NotMember });
+        }
+        if group.epoch != epoch {
+            return Err(GroupError::StaleKey);
+        }
+        Ok(group.members[from].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> Vec<u8> {
+        vec![byte; 32]
+    }
+
+    // Группа &team: владелец alice, участник bob (эпоха 2).
+    fn team() -> GroupRegistry {
+        let mut groups = GroupRegistry::default();
+        groups.create("&team", "alice", key(1)).unwrap();
+        groups.invite("&team", "alice", "bob").unwrap();
+        groups.accept("&team", "bob", key(2)).unwrap();
+        groups
+    }
+
+    #[test]
+    fn group_names_follow_room_rules_with_own_prefix() {
+        assert_eq!(normalize_group_name(" &Team-1 ").as_deref(), Some("&team-1"));
+        assert_eq!(normalize_group_name("team_1").as_deref(), Some("&team_1"));
+        assert_eq!(normalize_group_name("&"), None);
+        assert_eq!(normalize_group_name("two words"), None);
+        assert_eq!(normalize_group_name(&"a".repeat(33)), None);
+    }
+
+    #[test]
+    fn every_membership_change_starts_a_new_epoch() {
+        let mut groups = GroupRegistry::default();
+        assert_eq!(groups.create("&team", "alice", key(1)).unwrap().epoch, 1);
+        assert_eq!(groups.create("&team", "bob", key(2)).unwrap_err(), GroupError::Exists);
+
+        groups.invite("&team", "alice", "bob").unwrap();
+        let info = groups.accept("&team", "bob", key(2)).unwrap();
+        assert_eq!(info.epoch, 2);
+        assert_eq!(info.members.len(), 2);
+
+        groups.invite("&team", "alice", "carol").unwrap();
+        groups.decline("&team", "carol").unwrap();
+        assert_eq!(groups.groups_of("alice")[0].epoch, 2);
+
+        assert_eq!(groups.leave("&team", "bob").unwrap().unwrap().epoch, 3);
+    }
+
+    #[test]
+    fn messages_and_keys_require_current_epoch() {
+        let groups = team();
+        assert_eq!(groups.recipients("&team", "bob", 2).unwrap(), ["alice"]);
+        assert_eq!(groups.recipients("&team", "bob", 1).unwrap_err(), GroupError::StaleKey);
+        assert_eq!(groups.recipients("&team", "carol", 2).unwrap_err(), GroupError::NotMember);
+
+        assert_eq!(groups.key_owner("&team", "alice", 2).unwrap(), key(1));
+        assert_eq!(groups.key_owner("&team", "alice", 1).unwrap_err(), GroupError::StaleKey);
+        assert_eq!(groups.key_owner("&team", "bob", 2).unwrap_err(), GroupError::NotOwner);
+    }
+
+    #[test]
+    fn only_owner_invites_and_only_invited_join() {
+        let mut groups = team();
+        assert_eq!(groups.invite("&team", "bob", "carol").unwrap_err(), GroupError::NotOwner);
+        assert_eq!(groups.invite("&team", "carol", "dave").unwrap_err(), GroupError::NotMember);
+        assert_eq!(groups.invite("&team", "alice", "bob").unwrap_err(), GroupError::AlreadyMember);
+        assert_eq!(groups.accept("&team", "carol", key(3)).unwrap_err(), GroupError::NotInvited);
+        assert_eq!(groups.invite("&nope", "alice", "bob").unwrap_err(), GroupError::NotFound);
+    }
+
+    #[test]
+    fn owner_leaving_passes_ownership_and_last_member_deletes_group() {
+        let mut groups = team();
+        groups.invite("&team", "alice", "carol").unwrap();
+        groups.accept("&team", "carol", key(3)).unwrap();
+
+        let info = groups.leave("&team", "alice").unwrap().unwrap();
+        assert_eq!(info.owner, "bob");
+        assert_eq!(info.epoch, 4);
+        assert_eq!(groups.key_owner("&team", "bob", 4).unwrap(), key(2));
+
+        let remaining = groups.remove_user("bob");
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].owner, "carol");
+
+        assert!(groups.leave("&team", "carol").unwrap().is_none());
+        assert!(groups.groups_of("carol").is_empty());
+        assert_eq!(groups.leave("&team", "carol").unwrap_err(), GroupError::NotFound);
+    }
+
+    #[test]
+    fn disconnect_drops_pending_invitations() {
+        let mut groups = team();
+        groups.invite("&team", "alice", "carol").unwrap();
+        assert!(groups.remove_user("carol").is_empty());
+        assert_eq!(groups.accept("&team", "carol", key(3)).unwrap_err(), GroupError::NotInvited);
+    }
+}
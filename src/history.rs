@@ -0,0 +1,76 @@
+use crate::event::HistoryEntry;
+use crate::log::{log_message, LogLevel};
+use chrono::Local;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::path::Path;
+use tokio::fs::{File as TokioFile, OpenOptions as TokioOpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+
+// Сколько последних сообщений каждой комнаты держим в памяти; больше /history не покажет.
+pub const MAX_HISTORY_REPLAY: usize = 100;
+
+// Журнал сообщений комнат: файл JSON Lines, в который только дописывают,
+// и кэш последних сообщений каждой комнаты для /history.
+pub struct MessageHistory {
+    file: Mutex<TokioFile>,
+    recent: Mutex<HashMap<String, VecDeque<HistoryEntry>>>,
+}
+
+fn remember(recent: &mut HashMap<String, VecDeque<HistoryEntry>>, entry: HistoryEntry) {
+    let room = recent.entry(entry.room.clone()).or_default();
+    if room.len() == MAX_HISTORY_REPLAY {
+        room.pop_front();
+    }
+    room.push_back(entry);
+}
+
+impl MessageHistory {
+    pub async fn open(path: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut recent = HashMap::new();
+        let mut loaded = 0;
+        if Path::new(path).exists() {
+            let mut lines = BufReader::new(TokioFile::open(path).await?).lines();
+            while let Some(line) = lines.next_line().await? {
+                match serde_json::from_str::<HistoryEntry>(&line) {
+                    Ok(entry) => {
+                        remember(&mut recent, entry);
+                        loaded += 1;
+                    }
+                    Err(e) if !line.trim().is_empty() => {
+                        log_message(LogLevel::Warn, "history", &format!("Пропущена повреждённая строка истории в {}: {}", path, e));
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+        let file = TokioOpenOptions::new().append(true).create(true).open(path).await?;
+        log_message(LogLevel::Info, "history", &format!("Загружено {} сообщений истории из {}", loaded, path));
+        Ok(MessageHistory { file: Mutex::new(file), recent: Mutex::new(recent) })
+    }
+
+    pub async fn record(&self, room: &str, from: &str, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let entry = HistoryEntry {
+            timestamp: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            room: room.to_string(),
+            from: from.to_string(),
+            text: text.to_string(),
+        };
+        let line = format!("{}\n", serde_json::to_string(&entry)?);
+        // Порядок в файле и в кэше совпадает, поэтому кэш обновляется под блокировкой файла.
+        let mut file = self.file.lock().await;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+        remember(&mut *self.recent.lock().await, entry);
+        Ok(())
+    }
+
+    pub async fn recent(&self, room: &str, limit: usize) -> Vec<HistoryEntry> {
+        let recent = self.recent.lock().await;
+        let Some(entries) = recent.get(room) else {
+            return Vec::new();
+        };
+        entries.iter().skip(entries.len().saturating_sub(limit)).cloned().collect()
+    }
+}
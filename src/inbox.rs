@@ -0,0 +1,71 @@
+use crate::event::OfflineMessage;
+use crate::log::{log_message, LogLevel};
+use chrono::Local;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+// Личные сообщения для зарегистрированных пользователей, которые сейчас не в сети.
+// Хранятся в JSON-файле вида {"ник": [сообщения]}, файл перезаписывается при каждом изменении.
+pub struct OfflineInbox {
+    path: String,
+    limit: usize,
+    queues: BTreeMap<String, Vec<OfflineMessage>>,
+}
+
+impl OfflineInbox {
+    pub async fn load(path: &str, limit: usize) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let queues: BTreeMap<String, Vec<OfflineMessage>> = if Path::new(path).exists() {
+            let text = tokio::fs::read_to_string(path).await?;
+            if text.trim().is_empty() {
+                BTreeMap::new()
+            } else {
+                serde_json::from_str(&text).map_err(|e| format!("Ошибка в файле {}: {}", path, e))?
+            }
+        } else {
+            BTreeMap::new()
+        };
+        let pending: usize = queues.values().map(Vec::len).sum();
+        log_message(LogLevel::Info, "inbox", &format!("Загружено {} недоставленных сообщений из {}", pending, path));
+        Ok(OfflineInbox { path: path.to_string(), limit, queues })
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    // Возвращает false, если ящик получателя заполнен.
+    pub fn push(&mut self, recipient: &str, from: &str, text: &str) -> bool {
+        let queue = self.queues.entry(recipient.to_string()).or_default();
+        if queue.len() >= self.limit {
+            return false;
+        }
+        queue.push(OfflineMessage {
+            timestamp: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            from: from.to_string(),
+            text: text.to_string(),
+        });
+        true
+    }
+
+    pub fn pending(&self, nick: &str) -> Vec<OfflineMessage> {
+        self.queues.get(nick).cloned().unwrap_or_default()
+    }
+
+    // Удаляет первые count сообщений, которые уже доставлены.
+    pub fn acknowledge(&mut self, nick: &str, count: usize) {
+        if let Some(queue) = self.queues.get_mut(nick) {
+            queue.drain(..count.min(queue.len()));
+            if queue.is_empty() {
+                self.queues.remove(nick);
+            }
+        }
+    }
+
+    pub async fn save(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let tmp_path = format!("{}.tmp", self.path);
+        tokio::fs::write(&tmp_path, serde_json::to_string_pretty(&self.queues)?).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+}
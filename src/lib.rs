@@ -0,0 +1,22 @@
+pub mod auth;
+pub mod client;
+pub mod config;
+pub mod e2e;
+pub mod event;
+pub mod groups;
+pub mod history;
+pub mod inbox;
+pub mod log;
+pub mod message;
+pub mod moderation;
+pub mod outbound;
+pub mod password;
+pub mod protocol;
+pub mod ratelimit;
+pub mod rooms;
+pub mod server;
+pub mod tls;
+pub mod users;
+
+pub use config::ServerConfig;
+pub use server::{ChatServer, ServerHandle, ServerState};
@@ -1,28 +1,654 @@
+use std::cell::RefCell;
 use std::error::Error;
-use tokio::fs::File as TokioFile;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::fs::{File as TokioFile, OpenOptions as TokioOpenOptions};
 use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::MissedTickBehavior;
 use colored::{Colorize, Color};
-use chrono::Local;
-use once_cell::sync::Lazy;
-use std::sync::Arc;
-
-static LOG_FILE: Lazy<Arc<Mutex<TokioFile>>> = Lazy::new(|| {
-    Arc::new(Mutex::new(
-        TokioFile::from_std(std::fs::File::create("server.log").expect("Не удалось создать или открыть файл логов"))
-    ))
-});
-
-pub async fn log_message(log_type: &str, message: &str, color: Color) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let now = Local::now();
-    let timestamp = now.format("%Y-%m-%d %H:%M:%S").to_string();
-    let colored_log_type = format!("[{}]", log_type).color(color);
-    let log_entry_console = format!("{} {} {}\n", timestamp, colored_log_type, message);
-
-    println!("{}", log_entry_console.trim_end());
-
-    let mut file_guard = LOG_FILE.lock().await;
-    file_guard.write_all(format!("{} [{}] {}\n", timestamp, log_type, message).as_bytes()).await?;
-    file_guard.flush().await?;
+use chrono::{DateTime, Local, NaiveDate};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Deserialize;
+use crate::event::ServerEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    // Каждое событие, полученное и отправленное клиенту.
+    Trace = 4,
+}
+
+impl LogLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<LogLevel> {
+        [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace]
+            .into_iter()
+            .find(|level| level.name() == name.trim().to_lowercase())
+    }
+
+    fn color(&self) -> Color {
+        match self {
+            LogLevel::Error => Color::Red,
+            LogLevel::Warn => Color::Yellow,
+            LogLevel::Info => Color::Green,
+            LogLevel::Debug => Color::Cyan,
+            LogLevel::Trace => Color::BrightBlack,
+        }
+    }
+}
+
+// Формат записей в файле; в консоль всегда пишется текст.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    // Одна JSON-запись на строку.
+    Json,
+}
+
+// Что попадает в лог о сообщениях пользователей.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogContent {
+    // Кто, кому, когда и что написал.
+    Full,
+    // Кто, кому и когда, но без текста.
+    Metadata,
+    // О сообщениях пользователей не пишется ничего.
+    None,
+}
+
+// Поля, которые добавляются ко всем записям задачи подключения.
+#[derive(Debug, Clone, Default)]
+struct LogContext {
+    peer: Option<String>,
+    nick: Option<String>,
+}
+
+tokio::task_local! {
+    static LOG_CONTEXT: RefCell<LogContext>;
+}
+
+// Выполняет задачу подключения так, что её записи помечаются адресом клиента, а после входа и ником.
+pub fn with_log_context<F: Future>(peer: SocketAddr, task: F) -> impl Future<Output = F::Output> {
+    LOG_CONTEXT.scope(RefCell::new(LogContext { peer: Some(peer.to_string()), nick: None }), task)
+}
+
+pub fn set_log_nick(nick: &str) {
+    let _ = LOG_CONTEXT.try_with(|context| context.borrow_mut().nick = Some(nick.to_string()));
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogRotation {
+    // Размер файла, после которого он ротируется (0 — не ограничивать).
+    pub max_size_mb: u64,
+    // Ротировать при смене суток.
+    pub daily: bool,
+    // Сколько старых файлов хранить: server.log.1 — самый свежий.
+    pub keep: usize,
+    // Сжимать старые файлы в .gz.
+    pub compress: bool,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        LogRotation { max_size_mb: 10, daily: false, keep: 5, compress: false }
+    }
+}
+
+// Открытый файл логов и всё, что нужно, чтобы решить, пора ли его ротировать.
+struct LogFile {
+    file: TokioFile,
+    path: String,
+    format: LogFormat,
+    // Записи, ещё не переданные в файл.
+    pending: Vec<u8>,
+    size: u64,
+    day: NaiveDate,
+    rotation: LogRotation,
+}
+
+impl LogFile {
+    async fn open(path: &str, format: LogFormat, rotation: LogRotation) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let file = TokioOpenOptions::new().append(true).create(true).open(path).await?;
+        let metadata = file.metadata().await?;
+        // Дописываемый файл относится к суткам своего последнего изменения.
+        let day = metadata.modified().map(|time| DateTime::<Local>::from(time).date_naive()).unwrap_or_else(|_| Local::now().date_naive());
+        Ok(LogFile { file, path: path.to_string(), format, pending: Vec::new(), size: metadata.len(), day, rotation })
+    }
+
+    fn needs_rotation(&self, day: NaiveDate, incoming: u64) -> bool {
+        let max_size = self.rotation.max_size_mb.saturating_mul(1024 * 1024);
+        let too_big = max_size > 0 && self.size > 0 && self.size + incoming > max_size;
+        let new_day = self.rotation.daily && day != self.day;
+        too_big || new_day
+    }
+
+    async fn append(&mut self, record: &LogRecord) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let line = record.render(self.format);
+        let day = record.time.date_naive();
+        if self.needs_rotation(day, line.len() as u64) {
+            self.rotate(day).await?;
+        }
+        self.pending.extend_from_slice(line.as_bytes());
+        self.size += line.len() as u64;
+        if self.pending.len() >= LOG_BATCH_BYTES {
+            self.write_pending().await?;
+        }
+        Ok(())
+    }
+
+    async fn write_pending(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        // Неудачная пачка не копится бесконечно: она теряется, а ошибка уходит в stderr.
+        let pending = std::mem::take(&mut self.pending);
+        self.file.write_all(&pending).await?;
+        self.file.flush().await?;
+        Ok(())
+    }
+
+    // server.log.N удаляется, остальные сдвигаются на один номер, текущий файл становится server.log.1.
+    async fn rotate(&mut self, day: NaiveDate) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.write_pending().await?;
+        let keep = self.rotation.keep;
+        let rotated = |index: usize| format!("{}.{}", self.path, index);
+        for suffix in ["", ".gz"] {
+            let _ = tokio::fs::remove_file(format!("{}{}", rotated(keep.max(1)), suffix)).await;
+            for index in (1..keep).rev() {
+                let from = format!("{}{}", rotated(index), suffix);
+                if Path::new(&from).exists() {
+                    tokio::fs::rename(&from, format!("{}{}", rotated(index + 1), suffix)).await?;
+                }
+            }
+        }
+        if keep == 0 {
+            tokio::fs::remove_file(&self.path).await?;
+        } else {
+            tokio::fs::rename(&self.path, rotated(1)).await?;
+            if self.rotation.compress {
+                let source = rotated(1);
+                tokio::task::spawn_blocking(move || compress_file(&source)).await??;
+            }
+        }
+        self.file = TokioOpenOptions::new().append(true).create(true).open(&self.path).await?;
+        self.size = 0;
+        self.day = day;
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.write_pending().await?;
+        self.file.sync_all().await?;
+        Ok(())
+    }
+}
+
+fn compress_file(path: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut source = std::fs::File::open(path)?;
+    let mut encoder = GzEncoder::new(std::fs::File::create(format!("{}.gz", path))?, Compression::default());
+    std::io::copy(&mut source, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    std::fs::remove_file(path)?;
     Ok(())
 }
+
+// Записи уходят задаче записи через ограниченную очередь; при переполнении они отбрасываются,
+// чтобы медленный диск не тормозил обработку клиентов.
+const LOG_QUEUE_SIZE: usize = 8192;
+// Накопленные записи сбрасываются в файл при таком объёме или раз в LOG_FLUSH_INTERVAL.
+const LOG_BATCH_BYTES: usize = 64 * 1024;
+const LOG_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+struct LogRecord {
+    time: DateTime<Local>,
+    level: LogLevel,
+    category: String,
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl LogRecord {
+    fn new(level: LogLevel, category: &str, message: &str, fields: &[(&str, &str)]) -> Self {
+        let context = LOG_CONTEXT.try_with(|context| context.borrow().clone()).unwrap_or_default();
+        let mut all_fields = Vec::new();
+        if let Some(nick) = context.nick {
+            all_fields.push(("nick".to_string(), nick));
+        }
+        if let Some(peer) = context.peer {
+            all_fields.push(("peer".to_string(), peer));
+        }
+        all_fields.extend(fields.iter().map(|(key, value)| (key.to_string(), value.to_string())));
+        LogRecord { time: Local::now(), level, category: category.to_string(), message: message.to_string(), fields: all_fields }
+    }
+
+    fn print(&self) {
+        let timestamp = self.time.format("%Y-%m-%d %H:%M:%S");
+        let level_label = format!("{:<5}", self.level.name().to_uppercase());
+        println!("{} {} [{}] {}{}", timestamp, level_label.color(self.level.color()), self.category, self.message, self.rendered_fields().dimmed());
+    }
+
+    fn rendered_fields(&self) -> String {
+        self.fields.iter().map(|(key, value)| format!(" {}={}", key, value)).collect()
+    }
+
+    fn render(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Text => {
+                let level_label = format!("{:<5}", self.level.name().to_uppercase());
+                format!("{} {} [{}] {}{}\n", self.time.format("%Y-%m-%d %H:%M:%S"), level_label, self.category, self.message, self.rendered_fields())
+            }
+            LogFormat::Json => {
+                let mut record = serde_json::Map::new();
+                record.insert("timestamp".to_string(), self.time.to_rfc3339().into());
+                record.insert("level".to_string(), self.level.name().into());
+                record.insert("category".to_string(), self.category.clone().into());
+                record.insert("message".to_string(), self.message.clone().into());
+                for (key, value) in &self.fields {
+                    record.insert(key.clone(), value.clone().into());
+                }
+                format!("{}\n", serde_json::Value::Object(record))
+            }
+        }
+    }
+}
+
+enum LogCommand {
+    Record(LogRecord),
+    Options(LogFormat, LogRotation),
+    Flush(oneshot::Sender<Result<(), Box<dyn Error + Send + Sync>>>),
+}
+
+// Файл логов одного сервера: очередь к его задаче записи, уровень логирования и политика
+// log_content этого сервера, счётчик записей, не попавших в файл.
+#[derive(Clone)]
+pub struct Logger {
+    writer: mpsc::Sender<LogCommand>,
+    level: Arc<AtomicU8>,
+    content: Arc<AtomicU8>,
+    dropped: Arc<AtomicU64>,
+}
+
+tokio::task_local! {
+    static LOGGER: Logger;
+}
+
+// Куда уходят записи вне задач сервера, например из main: в лог последнего открытого сервера.
+static DEFAULT_LOGGER: RwLock<Option<Logger>> = RwLock::new(None);
+
+impl Logger {
+    fn new(writer: mpsc::Sender<LogCommand>) -> Self {
+        Logger {
+            writer,
+            level: Arc::new(AtomicU8::new(LogLevel::Info as u8)),
+            content: Arc::new(AtomicU8::new(LogContent::Metadata as u8)),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // Открывает файл на дозапись, поэтому логи прошлых запусков сохраняются, и запускает задачу записи
+    // в текущем рантайме. Новый лог становится логом по умолчанию.
+    pub async fn open(path: &str, format: LogFormat, rotation: LogRotation) -> Result<Logger, Box<dyn Error + Send + Sync>> {
+        let file = LogFile::open(path, format, rotation).await?;
+        let (writer, commands) = mpsc::channel(LOG_QUEUE_SIZE);
+        let logger = Logger::new(writer);
+        tokio::spawn(log_writer(file, commands, logger.dropped.clone()));
+        *DEFAULT_LOGGER.write().unwrap_or_else(|e| e.into_inner()) = Some(logger.clone());
+        logger.send(LogRecord::new(LogLevel::Info, "server", &format!("Файл логов инициализирован: {}", path), &[]));
+        Ok(logger)
+    }
+
+    // Выполняет задачу так, что её записи уходят в этот лог. Порождённые задачи
+    // область не наследуют и оборачиваются отдельно.
+    pub fn scope<F: Future>(&self, task: F) -> impl Future<Output = F::Output> {
+        LOGGER.scope(self.clone(), task)
+    }
+
+    pub fn set_level(&self, level: LogLevel) {
+        self.level.store(level as u8, Ordering::Relaxed);
+    }
+
+    pub fn level(&self) -> LogLevel {
+        match self.level.load(Ordering::Relaxed) {
+            0 => LogLevel::Error,
+            1 => LogLevel::Warn,
+            2 => LogLevel::Info,
+            3 => LogLevel::Debug,
+            _ => LogLevel::Trace,
+        }
+    }
+
+    pub fn set_content(&self, content: LogContent) {
+        self.content.store(content as u8, Ordering::Relaxed);
+    }
+
+    pub fn content(&self) -> LogContent {
+        match self.content.load(Ordering::Relaxed) {
+            0 => LogContent::Full,
+            1 => LogContent::Metadata,
+            _ => LogContent::None,
+        }
+    }
+
+    // Сколько записей не попало в файл: из-за переполненной очереди или потому, что задача записи уже завершилась.
+    pub fn dropped_records(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub async fn set_file_options(&self, format: LogFormat, rotation: LogRotation) {
+        let _ = self.writer.send(LogCommand::Options(format, rotation)).await;
+    }
+
+    // Дожидается, пока задача записи сбросит на диск всё, что было записано до вызова.
+    pub async fn flush(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (done, flushed) = oneshot::channel();
+        self.writer.send(LogCommand::Flush(done)).await.map_err(|_| "задача записи логов завершилась")?;
+        flushed.await.map_err(|_| "задача записи логов завершилась")?
+    }
+
+    fn send(&self, record: LogRecord) {
+        match self.writer.try_send(LogCommand::Record(record)) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // Задача записи завершается вместе со своим рантаймом: запись хотя бы выводится в консоль.
+            Err(mpsc::error::TrySendError::Closed(command)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                if let LogCommand::Record(record) = command {
+                    record.print();
+                }
+            }
+        }
+    }
+
+    // Лог задачи, а вне задач сервера — лог по умолчанию.
+    fn current() -> Option<Logger> {
+        LOGGER
+            .try_with(Logger::clone)
+            .ok()
+            .or_else(|| DEFAULT_LOGGER.read().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+}
+
+// Единственная задача, которая выводит записи своего лога в консоль и пишет его файл: собирает записи в пачки,
+// ротирует файл и сообщает, сколько записей было отброшено из-за переполненной очереди.
+async fn log_writer(mut file: LogFile, mut commands: mpsc::Receiver<LogCommand>, dropped: Arc<AtomicU64>) {
+    let mut flush_timer = tokio::time::interval(LOG_FLUSH_INTERVAL);
+    flush_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut reported_dropped = 0;
+    loop {
+        let result = tokio::select! {
+            command = commands.recv() => match command {
+                Some(LogCommand::Record(record)) => {
+                    record.print();
+                    file.append(&record).await
+                }
+                Some(LogCommand::Options(format, rotation)) => {
+                    file.format = format;
+                    file.rotation = rotation;
+                    Ok(())
+                }
+                Some(LogCommand::Flush(done)) => {
+                    let _ = done.send(file.sync().await);
+                    Ok(())
+                }
+                None => {
+                    let _ = file.sync().await;
+                    return;
+                }
+            },
+            _ = flush_timer.tick() => {
+                let dropped = dropped.load(Ordering::Relaxed);
+                let mut result = Ok(());
+                if dropped > reported_dropped {
+                    let record = LogRecord::new(LogLevel::Warn, "server", &format!("Очередь логов переполнена, пропущено записей: {}", dropped - reported_dropped), &[]);
+                    record.print();
+                    reported_dropped = dropped;
+                    result = file.append(&record).await;
+                }
+                result.and(file.write_pending().await)
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Ошибка записи в файл логов: {}", e);
+        }
+    }
+}
+
+// Политика log_content лога текущей задачи; пока ни один лог не открыт — metadata.
+pub fn log_content() -> LogContent {
+    Logger::current().map_or(LogContent::Metadata, |logger| logger.content())
+}
+
+// Уровень лога текущей задачи; пока ни один лог не открыт — info.
+pub fn log_level() -> LogLevel {
+    Logger::current().map_or(LogLevel::Info, |logger| logger.level())
+}
+
+// Позволяет не собирать дорогие поля для записей, которые всё равно будут отброшены.
+pub fn log_enabled(level: LogLevel) -> bool {
+    level <= log_level()
+}
+
+// Сбрасывает на диск лог текущей задачи. Ошибка, если его задача записи уже завершилась.
+pub async fn flush_log() -> Result<(), Box<dyn Error + Send + Sync>> {
+    match Logger::current() {
+        Some(logger) => logger.flush().await,
+        None => Ok(()),
+    }
+}
+
+pub fn log_message(level: LogLevel, category: &str, message: &str) {
+    log_fields(level, category, message, &[]);
+}
+
+// Запись о сообщении пользователя. content — его текст: он дописывается к записи только
+// при log_content = full, а при none записи о сообщениях не пишутся совсем.
+pub fn log_user_message(level: LogLevel, category: &str, message: &str, content: Option<&str>, fields: &[(&str, &str)]) {
+    match (log_content(), content) {
+        (LogContent::Full, Some(content)) => log_fields(level, category, &format!("{}: {}", message, content), fields),
+        (LogContent::Full | LogContent::Metadata, _) => log_fields(level, category, message, fields),
+        (LogContent::None, _) => {}
+    }
+}
+
+// Запись о событии для клиента с его типом в поле event. Само событие попадает в лог только
+// при log_content = full; события с текстом пользователей при none не пишутся совсем.
+pub fn log_event(level: LogLevel, category: &str, message: &str, event: &ServerEvent, fields: &[(&str, &str)]) {
+    if !log_enabled(level) {
+        return;
+    }
+    let kind = event.kind();
+    let mut all_fields = vec![("event", kind.as_str())];
+    all_fields.extend_from_slice(fields);
+    let full = log_content() == LogContent::Full;
+    if event.has_user_content() {
+        log_user_message(level, category, message, full.then(|| format!("{:?}", event)).as_deref(), &all_fields);
+    } else if full {
+        log_fields(level, category, &format!("{}: {:?}", message, event), &all_fields);
+    } else {
+        log_fields(level, category, message, &all_fields);
+    }
+}
+
+// Запись с дополнительными полями, например типом события. Ник и адрес клиента добавляются сами.
+// Вызывающий не ждёт вывода: и в консоль, и в файл запись выводит задача записи. Пока ни один
+// лог не открыт, запись сразу печатается в консоль.
+pub fn log_fields(level: LogLevel, category: &str, message: &str, fields: &[(&str, &str)]) {
+    if !log_enabled(level) {
+        return;
+    }
+    let record = LogRecord::new(level, category, message, fields);
+    match Logger::current() {
+        Some(logger) => logger.send(record),
+        None => record.print(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Выполняет f с логом на уровне trace и с заданной политикой содержимого; записи не пишутся в файл,
+    // а возвращаются в виде JSON-строк.
+    async fn capture(content: LogContent, f: impl FnOnce()) -> Vec<String> {
+        let (writer, mut commands) = mpsc::channel(16);
+        let logger = Logger::new(writer);
+        logger.set_level(LogLevel::Trace);
+        logger.set_content(content);
+        logger.scope(async { f() }).await;
+        drop(logger);
+        let mut lines = Vec::new();
+        while let Some(command) = commands.recv().await {
+            if let LogCommand::Record(record) = command {
+                lines.push(record.render(LogFormat::Json));
+            }
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn each_logger_filters_by_its_own_level() {
+        let (quiet_writer, mut quiet) = mpsc::channel(4);
+        let (verbose_writer, mut verbose) = mpsc::channel(4);
+        let quiet_logger = Logger::new(quiet_writer);
+        let verbose_logger = Logger::new(verbose_writer);
+        verbose_logger.set_level(LogLevel::Debug);
+        for logger in [&quiet_logger, &verbose_logger] {
+            logger.scope(async { log_message(LogLevel::Debug, "server", "подробности") }).await;
+        }
+        assert!(quiet.try_recv().is_err());
+        assert!(matches!(verbose.try_recv(), Ok(LogCommand::Record(_))));
+        assert_eq!(quiet_logger.level(), LogLevel::Info);
+    }
+
+    fn log_secret() {
+        log_user_message(LogLevel::Info, "message", "'alice' отправил прямое сообщение 'bob'", Some("пароль от сейфа"), &[]);
+        let event = ServerEvent::DirectMessage { from: "alice".to_string(), text: "пароль от сейфа".to_string() };
+        log_event(LogLevel::Trace, "message", "Сообщение отправлено 'bob'", &event, &[("to", "bob")]);
+        log_event(LogLevel::Trace, "message", "Сообщение отправлено 'bob'", &ServerEvent::notice("pm_sent", "ok"), &[("to", "bob")]);
+    }
+
+    #[tokio::test]
+    async fn metadata_policy_logs_who_but_not_what() {
+        let lines = capture(LogContent::Metadata, log_secret).await;
+        assert_eq!(lines.len(), 3, "{:?}", lines);
+        assert!(lines.iter().all(|line| !line.contains("пароль от сейфа")), "{:?}", lines);
+        assert!(lines[1].contains("\"event\":\"direct_message\"") && lines[1].contains("\"to\":\"bob\""), "{}", lines[1]);
+    }
+
+    #[tokio::test]
+    async fn none_policy_drops_records_about_user_messages() {
+        let lines = capture(LogContent::None, log_secret).await;
+        // Остаётся только событие без текста пользователей.
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        assert!(lines[0].contains("\"event\":\"system_notice\""), "{}", lines[0]);
+    }
+
+    #[tokio::test]
+    async fn full_policy_logs_message_text() {
+        let lines = capture(LogContent::Full, log_secret).await;
+        assert_eq!(lines.len(), 3, "{:?}", lines);
+        assert!(lines[..2].iter().all(|line| line.contains("пароль от сейфа")), "{:?}", lines);
+    }
+
+    #[tokio::test]
+    async fn content_policy_belongs_to_each_logger() {
+        let (writer, _commands) = mpsc::channel(16);
+        let full = Logger::new(writer.clone());
+        full.set_content(LogContent::Full);
+        let quiet = Logger::new(writer);
+        quiet.set_content(LogContent::None);
+        assert_eq!(full.scope(async { log_content() }).await, LogContent::Full);
+        assert_eq!(quiet.scope(async { log_content() }).await, LogContent::None);
+    }
+
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("kursovik-log-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Файл логов, уже заполненный до предела в 1 МБ, и записи прошлых ротаций .1 и .2.
+    async fn full_log(dir: &Path, compress: bool) -> (LogFile, String) {
+        let path = dir.join("server.log").to_string_lossy().into_owned();
+        std::fs::write(&path, vec![b'x'; 1024 * 1024]).unwrap();
+        let suffix = if compress { ".gz" } else { "" };
+        std::fs::write(format!("{}.1{}", path, suffix), "old-1").unwrap();
+        std::fs::write(format!("{}.2{}", path, suffix), "old-2").unwrap();
+        let rotation = LogRotation { max_size_mb: 1, daily: false, keep: 2, compress };
+        (LogFile::open(&path, LogFormat::Text, rotation).await.unwrap(), path)
+    }
+
+    #[tokio::test]
+    async fn rotation_is_due_on_size_limit_or_new_day() {
+        let dir = test_dir("due");
+        let (mut file, _) = full_log(&dir, false).await;
+        let today = file.day;
+        assert!(file.needs_rotation(today, 1));
+        assert!(!file.needs_rotation(today, 0));
+
+        file.size = 0;
+        // Одна запись больше предела всё равно пишется в пустой файл.
+        assert!(!file.needs_rotation(today, 2 * 1024 * 1024));
+        assert!(!file.needs_rotation(today.succ_opt().unwrap(), 1));
+        file.rotation.daily = true;
+        assert!(file.needs_rotation(today.succ_opt().unwrap(), 1));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn size_rotation_shifts_old_files_and_drops_the_oldest() {
+        let dir = test_dir("rotate");
+        let (mut file, path) = full_log(&dir, false).await;
+        file.append(&LogRecord::new(LogLevel::Info, "server", "после ротации", &[])).await.unwrap();
+        file.sync().await.unwrap();
+
+        let current = std::fs::read_to_string(&path).unwrap();
+        assert!(current.ends_with("[server] после ротации\n"), "{:?}", current);
+        assert_eq!(std::fs::read(format!("{}.1", path)).unwrap(), vec![b'x'; 1024 * 1024]);
+        assert_eq!(std::fs::read_to_string(format!("{}.2", path)).unwrap(), "old-1");
+        assert!(!Path::new(&format!("{}.3", path)).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn compressed_rotation_writes_gzip_and_shifts_gz_files() {
+        let dir = test_dir("compress");
+        let (mut file, path) = full_log(&dir, true).await;
+        file.append(&LogRecord::new(LogLevel::Info, "server", "после ротации", &[])).await.unwrap();
+        file.sync().await.unwrap();
+
+        assert!(!Path::new(&format!("{}.1", path)).exists());
+        let mut decoder = flate2::read::GzDecoder::new(std::fs::File::open(format!("{}.1.gz", path)).unwrap());
+        let mut rotated = Vec::new();
+        std::io::Read::read_to_end(&mut decoder, &mut rotated).unwrap();
+        assert_eq!(rotated, vec![b'x'; 1024 * 1024]);
+        assert_eq!(std::fs::read_to_string(format!("{}.2.gz", path)).unwrap(), "old-1");
+        assert!(!Path::new(&format!("{}.3.gz", path)).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
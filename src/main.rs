@@ -1,16 +1,32 @@
-mod auth;
-mod client;
-mod log;
-mod message;
-mod users;
-use tokio::net::TcpListener;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use std::collections::HashMap;
+use clap::Parser;
 use std::error::Error;
-use log::log_message;
-use users::{load_users};
-use client::handle_client;
+use std::path::Path;
+use kursovik::config::CliArgs;
+use kursovik::log::{flush_log, log_message, LogLevel};
+use kursovik::tls::generate_self_signed;
+use kursovik::{ChatServer, ServerConfig};
+
+// Ждёт SIGINT или SIGTERM (в Windows — Ctrl+C).
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let name = {
+        use tokio::signal::unix::{signal, SignalKind};
+        let (Ok(mut interrupt), Ok(mut terminate)) = (signal(SignalKind::interrupt()), signal(SignalKind::terminate())) else {
+            eprintln!("Не удалось установить обработчики сигналов");
+            return std::future::pending().await;
+        };
+        tokio::select! {
+            _ = interrupt.recv() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    };
+    #[cfg(not(unix))]
+    let name = {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl+C"
+    };
+    log_message(LogLevel::Info, "server", &format!("Получен {}, сервер останавливается.", name));
+}
 
 #[tokio::main]
 
@@ -19,36 +35,46 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     {
         use std::process::Command;
         let _ = Command::new("cmd")
-            .args(&["/C", "chcp 65001"])
+            .args(["/C", "chcp 65001"])
             .status();
     }
 
-    let _ = tokio::fs::File::create("server.log").await?;
-    log_message("Server", "Файл логов инициализирован.", colored::Color::White).await?;
-
-    let users_db = Arc::new(Mutex::new(load_users("users.txt").await?));
-    let connected_users = Arc::new(Mutex::new(HashMap::new()));
-
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    log_message("Server", "Сервер запущен на 127.0.0.1:8080", colored::Color::Green).await?;
-
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        log_message("Info", &format!("Новое подключение: {}", addr), colored::Color::Yellow).await?;
+    let args = CliArgs::parse();
+    let config = ServerConfig::from_args(&args)?;
 
-        let users_db_clone = users_db.clone();
-        let connected_users_clone = connected_users.clone();
+    if args.generate_cert {
+        let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) else {
+            return Err("Для --generate-cert укажите пути --tls-cert и --tls-key".into());
+        };
+        let mut names = vec!["localhost".to_string()];
+        if let Some((host, _)) = config.bind_addr.rsplit_once(':') {
+            if host != "localhost" {
+                names.push(host.to_string());
+            }
+        }
+        generate_self_signed(Path::new(cert), Path::new(key), names)?;
+        println!("Самоподписанный сертификат записан в {}, ключ — в {}", cert, key);
+        return Ok(());
+    }
 
+    let server = ChatServer::new(config).await?;
+    let handle = server.bind().await?;
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = signal(SignalKind::hangup())?;
+        let state = handle.state().clone();
         tokio::spawn(async move {
-            let client_addr = addr;
-            match handle_client(socket, users_db_clone, connected_users_clone).await {
-                Ok(_) => {
-                    let _ = log_message("Client", &format!("Клиент {} отключился корректно.", client_addr), colored::Color::Yellow).await;
-                },
-                Err(e) => {
-                    let _ = log_message("ERROR", &format!("Ошибка с клиентом {}: {:?} Клиент отключился с ошибкой.", client_addr, e), colored::Color::Red).await;
-                },
+            while hangup.recv().await.is_some() {
+                log_message(LogLevel::Info, "server", "Получен SIGHUP, перечитываю файл пользователей и конфигурацию.");
+                if let Err(e) = state.reload().await {
+                    log_message(LogLevel::Error, "server", &format!("Ошибка перезагрузки: {}", e));
+                }
             }
         });
     }
-}
\ No newline at end of file
+    let result = handle.run_until(shutdown_signal()).await;
+    // При ошибке сервер останавливается, не дойдя до сброса логов.
+    let _ = flush_log().await;
+    result
+}
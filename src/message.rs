@@ -1,53 +1,103 @@
 use std::collections::HashMap;
 use tokio::sync::Mutex;
 use std::sync::Arc;
-use crate::log::log_message;
-use colored::Color;
-use tokio::sync::mpsc::UnboundedSender;
+use crate::event::ServerEvent;
+use crate::log::{log_enabled, log_event, log_message, log_user_message, LogLevel};
+use crate::outbound::ClientSender;
+use crate::server::Rooms;
 
-type Tx = UnboundedSender<String>;
+pub type Tx = ClientSender;
+
+// Очереди получателей копируются из-под блокировки: при политике block отправка может подождать,
+// и ожидание не должно задерживать всех остальных.
+async fn deliver(recipients: Vec<Tx>, event: ServerEvent) {
+    for tx in recipients {
+        let _ = tx.send(event.clone()).await;
+    }
+}
 
 pub async fn broadcast_message(
     connected_users: &Arc<Mutex<HashMap<String, Tx>>>,
     sender: &str,
-    message: &str,
-    is_system_message: bool,
+    event: ServerEvent,
 ) {
-    let users = connected_users.lock().await;
-    for (nick, tx) in users.iter() {
-        if nick != sender {
-            let full_msg = if is_system_message {
-                format!("{}\n", message)
-            } else {
-                format!("{} {}: {}\n", colored::Colorize::blue("Всем"), sender, message)
-            };
-            let _ = tx.send(full_msg);
-        }
-    }
-    if !is_system_message {
-        log_message("Global message", &format!("'{}' отправил в общий чат: {}", sender, message), Color::Blue).await.unwrap_or_else(|e| eprintln!("Ошибка логирования широковещательного сообщения: {:?}", e));
+    let recipients = connected_users
+        .lock()
+        .await
+        .iter()
+        .filter(|(nick, _)| *nick != sender)
+        .map(|(_, tx)| tx.clone())
+        .collect();
+    deliver(recipients, event).await;
+}
+
+// Рассылка участникам одной комнаты, кроме отправителя.
+pub async fn broadcast_to_room(
+    connected_users: &Arc<Mutex<HashMap<String, Tx>>>,
+    rooms: &Rooms,
+    room: &str,
+    sender: &str,
+    event: ServerEvent,
+) {
+    let members = rooms.lock().await.members(room).unwrap_or_default();
+    let recipients = {
+        let users = connected_users.lock().await;
+        members.iter().filter(|nick| *nick != sender).filter_map(|nick| users.get(nick).cloned()).collect()
+    };
+    deliver(recipients, event.clone()).await;
+    if let ServerEvent::ChatMessage { room, from, text } = &event {
+        log_user_message(LogLevel::Info, "room", &format!("'{}' отправил сообщение в {}", from, room), Some(text), &[]);
     }
 }
 
+// Рассылка по списку ников, например участникам группы; кто не в сети, пропускается.
+pub async fn send_to_users(
+    connected_users: &Arc<Mutex<HashMap<String, Tx>>>,
+    recipients: &[String],
+    event: ServerEvent,
+) {
+    let recipients = {
+        let users = connected_users.lock().await;
+        recipients.iter().filter_map(|nick| users.get(nick).cloned()).collect()
+    };
+    deliver(recipients, event).await;
+}
+
+// Убирает пользователя из списка подключённых и отправляет ему последнее событие.
+// Очередь после этого закрывается: задача записи его сессии доотправляет её и завершается.
+pub async fn disconnect_user(
+    connected_users: &Arc<Mutex<HashMap<String, Tx>>>,
+    nick: &str,
+    event: ServerEvent,
+) -> bool {
+    let Some(tx) = connected_users.lock().await.remove(nick) else {
+        return false;
+    };
+    tx.close_with(event);
+    true
+}
+
 pub async fn send_to_user(
     connected_users: &Arc<Mutex<HashMap<String, Tx>>>,
     recipient_nick: &str,
-    message: String,
+    event: ServerEvent,
 ) -> Result<(), String> {
-    let users = connected_users.lock().await;
-    if let Some(tx) = users.get(recipient_nick) {
-        if tx.send(message.clone()).is_err() {
+    let recipient = connected_users.lock().await.get(recipient_nick).cloned();
+    if let Some(tx) = recipient {
+        let event_for_log = log_enabled(LogLevel::Trace).then(|| event.clone());
+        if let Err(e) = tx.send(event).await {
             let error_msg = format!("Не удалось отправить сообщение пользователю {}", recipient_nick);
-            log_message("ERROR", &format!("Канал к пользователю '{}' закрыт. Возможно, клиент отключился. Ошибка: {}", recipient_nick, error_msg), Color::Red).await.unwrap_or_else(|e| eprintln!("Ошибка логирования send_to_user: {:?}", e));
+            log_message(LogLevel::Warn, "message", &format!("Канал к пользователю '{}' закрыт ({}). Возможно, клиент отключился. Ошибка: {}", recipient_nick, e, error_msg));
             Err(error_msg)
         } else {
-            log_message("Sent", &format!("Сообщение отправлено '{}' : {}", recipient_nick, message.trim_end()), Color::Green).await.unwrap_or_else(|e| eprintln!("Ошибка логирования send_to_user: {:?}", e));
+            if let Some(event) = event_for_log {
+                log_event(LogLevel::Trace, "message", &format!("Сообщение отправлено '{}'", recipient_nick), &event, &[("to", recipient_nick)]);
+            }
             Ok(())
         }
     } else {
         let error_msg = format!("Пользователь {} не найден или не в сети.", recipient_nick);
-        log_message("ERROR", &format!("Пользователь '{}' не найден в connected_users. Ошибка: {}", recipient_nick, error_msg), Color::Red).await.unwrap_or_else(|e| eprintln!("Ошибка логирования send_to_user: {:?}", e));
+        log_message(LogLevel::Warn, "message", &format!("Пользователь '{}' не найден в connected_users. Ошибка: {}", recipient_nick, error_msg));
         Err(error_msg)
     }
 }
-
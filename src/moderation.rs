@@ -0,0 +1,105 @@
+use crate::log::{log_message, LogLevel};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub by: String,
+    pub reason: Option<String>,
+    pub since: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mute {
+    pub by: String,
+    // Unix-время, до которого пользователь не может писать в комнаты.
+    pub until: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ModerationData {
+    #[serde(default)]
+    bans: BTreeMap<String, Ban>,
+    #[serde(default)]
+    mutes: BTreeMap<String, Mute>,
+}
+
+pub fn format_time(unix: i64) -> String {
+    DateTime::from_timestamp(unix, 0)
+        .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| unix.to_string())
+}
+
+// Баны и заглушения, назначенные администраторами. Хранятся в JSON-файле,
+// который перезаписывается при каждом изменении.
+pub struct Moderation {
+    path: String,
+    data: ModerationData,
+}
+
+impl Moderation {
+    pub async fn load(path: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let data: ModerationData = if Path::new(path).exists() {
+            let text = tokio::fs::read_to_string(path).await?;
+            if text.trim().is_empty() {
+                ModerationData::default()
+            } else {
+                serde_json::from_str(&text).map_err(|e| format!("Ошибка в файле {}: {}", path, e))?
+            }
+        } else {
+            ModerationData::default()
+        };
+        log_message(LogLevel::Info, "moderation", &format!("Загружено банов: {}, заглушений: {} из {}", data.bans.len(), data.mutes.len(), path));
+        Ok(Moderation { path: path.to_string(), data })
+    }
+
+    // Возвращает false, если пользователь уже забанен.
+    pub fn ban(&mut self, nick: &str, by: &str, reason: Option<String>) -> bool {
+        if self.data.bans.contains_key(nick) {
+            return false;
+        }
+        let since = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        self.data.bans.insert(nick.to_string(), Ban { by: by.to_string(), reason, since });
+        true
+    }
+
+    pub fn unban(&mut self, nick: &str) -> bool {
+        self.data.bans.remove(nick).is_some()
+    }
+
+    pub fn ban_of(&self, nick: &str) -> Option<&Ban> {
+        self.data.bans.get(nick)
+    }
+
+    // Заглушает на seconds секунд и возвращает время окончания. Истёкшие записи заодно удаляются.
+    pub fn mute(&mut self, nick: &str, by: &str, seconds: u64) -> i64 {
+        let now = Local::now().timestamp();
+        self.data.mutes.retain(|_, mute| mute.until > now);
+        let until = now.saturating_add(i64::try_from(seconds).unwrap_or(i64::MAX));
+        self.data.mutes.insert(nick.to_string(), Mute { by: by.to_string(), until });
+        until
+    }
+
+    // Возвращает false, если пользователь не был заглушён.
+    pub fn unmute(&mut self, nick: &str) -> bool {
+        let now = Local::now().timestamp();
+        let was_muted = self.muted_until(nick).is_some();
+        self.data.mutes.remove(nick);
+        self.data.mutes.retain(|_, mute| mute.until > now);
+        was_muted
+    }
+
+    pub fn muted_until(&self, nick: &str) -> Option<i64> {
+        self.data.mutes.get(nick).map(|mute| mute.until).filter(|until| *until > Local::now().timestamp())
+    }
+
+    pub async fn save(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let tmp_path = format!("{}.tmp", self.path);
+        tokio::fs::write(&tmp_path, serde_json::to_string_pretty(&self.data)?).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+use crate::event::ServerEvent;
+use crate::log::{log_message, LogLevel};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+// Что делать, когда очередь событий клиента заполнена.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowClientPolicy {
+    // Выбросить самое старое событие из очереди.
+    DropOldest,
+    // Сразу отключить клиента.
+    DropClient,
+    // Подождать до block_ms, пока клиент освободит место, и только потом отключить.
+    Block,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConfig {
+    // Сколько событий может ждать отправки одному клиенту.
+    pub queue_size: usize,
+    pub policy: SlowClientPolicy,
+    pub block_ms: u64,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        OutboundConfig { queue_size: 256, policy: SlowClientPolicy::DropClient, block_ms: 200 }
+    }
+}
+
+// Счётчики по всем подключениям сервера.
+#[derive(Debug, Default)]
+pub struct OutboundStats {
+    slow_disconnects: AtomicU64,
+    dropped_events: AtomicU64,
+}
+
+impl OutboundStats {
+    pub fn slow_disconnects(&self) -> u64 {
+        self.slow_disconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    // Сессия уже завершается.
+    Closed,
+    // Клиент не успевал получать события и отключён.
+    TooSlow,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Closed => write!(f, "канал к клиенту закрыт"),
+            SendError::TooSlow => write!(f, "клиент отключён: не успевает получать сообщения"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    events: VecDeque<ServerEvent>,
+    closed: bool,
+    // Сколько событий выброшено с последней проверки (политика drop_oldest).
+    dropped: u64,
+}
+
+struct Queue {
+    // Ник получателя, для записи в лог.
+    nick: String,
+    state: Mutex<QueueState>,
+    readable: Notify,
+    writable: Notify,
+    slow: watch::Sender<bool>,
+    config: OutboundConfig,
+    stats: Arc<OutboundStats>,
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn capacity(&self) -> usize {
+        self.config.queue_size.max(1)
+    }
+
+    fn disconnect_slow(&self) -> SendError {
+        {
+            let mut state = self.lock();
+            if state.closed {
+                return SendError::Closed;
+            }
+            state.closed = true;
+            state.events.clear();
+        }
+        let total = self.stats.slow_disconnects.fetch_add(1, Ordering::Relaxed) + 1;
+        log_message(
+            LogLevel::Warn,
+            "outbound",
+            &format!("'{}' отключён: очередь из {} событий переполнена, клиент не успевает их получать. Всего таких отключений: {}", self.nick, self.capacity(), total),
+        );
+        self.slow.send_replace(true);
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+        SendError::TooSlow
+    }
+}
+
+// Ограниченная очередь событий одного клиента. Её читает задача записи сессии,
+// а пишут все, кто рассылает события этому клиенту.
+pub fn client_queue(nick: &str, config: &OutboundConfig, stats: Arc<OutboundStats>) -> (ClientSender, ClientReceiver) {
+    let queue = Arc::new(Queue {
+        nick: nick.to_string(),
+        state: Mutex::new(QueueState::default()),
+        readable: Notify::new(),
+        writable: Notify::new(),
+        slow: watch::Sender::new(false),
+        config: config.clone(),
+        stats,
+    });
+    (ClientSender { queue: queue.clone() }, ClientReceiver { queue })
+}
+
+#[derive(Clone)]
+pub struct ClientSender {
+    queue: Arc<Queue>,
+}
+
+impl ClientSender {
+    pub async fn send(&self, event: ServerEvent) -> Result<(), SendError> {
+        let queue = &self.queue;
+        let deadline = Instant::now() + Duration::from_millis(queue.config.block_ms);
+        loop {
+            let writable = queue.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+            {
+                let mut state = queue.lock();
+                if state.closed {
+                    return Err(SendError::Closed);
+                }
+                if state.events.len() < queue.capacity() {
+                    state.events.push_back(event);
+                    drop(state);
+                    queue.readable.notify_one();
+                    return Ok(());
+                }
+                match queue.config.policy {
+                    SlowClientPolicy::DropOldest => {
+                        state.events.pop_front();
+                        state.events.push_back(event);
+                        state.dropped += 1;
+                        drop(state);
+                        queue.stats.dropped_events.fetch_add(1, Ordering::Relaxed);
+                        queue.readable.notify_one();
+                        return Ok(());
+                    }
+                    SlowClientPolicy::Block if Instant::now() < deadline => {}
+                    SlowClientPolicy::DropClient | SlowClientPolicy::Block => {
+                        drop(state);
+                        return Err(queue.disconnect_slow());
+                    }
+                }
+            }
+            let _ = tokio::time::timeout_at(deadline, writable).await;
+        }
+    }
+
+    // Последнее событие перед отключением (/kick, /ban): кладётся даже в полную очередь,
+    // после него сессия дочитывает очередь и завершается.
+    pub fn close_with(&self, event: ServerEvent) {
+        {
+            let mut state = self.queue.lock();
+            if state.closed {
+                return;
+            }
+            state.events.push_back(event);
+            state.closed = true;
+        }
+        self.queue.readable.notify_one();
+        self.queue.writable.notify_waiters();
+    }
+
+    // Закрывает очередь без нового события: сессия доотправит то, что уже в ней, и завершится.
+    pub fn close(&self) {
+        self.queue.lock().closed = true;
+        self.queue.readable.notify_one();
+        self.queue.writable.notify_waiters();
+    }
+
+    pub fn downgrade(&self) -> WeakClientSender {
+        WeakClientSender { queue: Arc::downgrade(&self.queue) }
+    }
+
+    pub fn same_queue(&self, other: &WeakClientSender) -> bool {
+        Weak::as_ptr(&other.queue) == Arc::as_ptr(&self.queue)
+    }
+}
+
+// Слабая ссылка на очередь не продлевает жизнь сессии.
+#[derive(Clone)]
+pub struct WeakClientSender {
+    queue: Weak<Queue>,
+}
+
+impl WeakClientSender {
+    pub fn upgrade(&self) -> Option<ClientSender> {
+        self.queue.upgrade().map(|queue| ClientSender { queue })
+    }
+}
+
+pub struct ClientReceiver {
+    queue: Arc<Queue>,
+}
+
+impl ClientReceiver {
+    // None, когда очередь закрыта и разобрана до конца.
+    pub async fn recv(&mut self) -> Option<ServerEvent> {
+        loop {
+            let readable = self.queue.readable.notified();
+            {
+                let mut state = self.queue.lock();
+                if let Some(event) = state.events.pop_front() {
+                    drop(state);
+                    self.queue.writable.notify_waiters();
+                    return Some(event);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            readable.await;
+        }
+    }
+
+    // Сколько событий выброшено с прошлого вызова.
+    pub fn take_dropped(&self) -> u64 {
+        std::mem::take(&mut self.queue.lock().dropped)
+    }
+
+    // Завершается, когда клиента отключают за то, что он не успевает получать события.
+    pub fn slow_disconnect(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut slow = self.queue.slow.subscribe();
+        async move {
+            let _ = slow.wait_for(|slow| *slow).await;
+        }
+    }
+}
+
+impl Drop for ClientReceiver {
+    // Сессия завершилась: тем, кто ещё держит отправителя, больше незачем ждать места в очереди.
+    fn drop(&mut self) {
+        self.queue.lock().closed = true;
+        self.queue.writable.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(queue_size: usize, policy: SlowClientPolicy, block_ms: u64) -> (ClientSender, ClientReceiver, Arc<OutboundStats>) {
+        let stats = Arc::new(OutboundStats::default());
+        let (tx, rx) = client_queue("bob", &OutboundConfig { queue_size, policy, block_ms }, stats.clone());
+        (tx, rx, stats)
+    }
+
+    fn event(n: usize) -> ServerEvent {
+        ServerEvent::notice("test", n.to_string())
+    }
+
+    async fn recv_text(rx: &mut ClientReceiver) -> Option<String> {
+        match rx.recv().await? {
+            ServerEvent::SystemNotice { text, .. } => Some(text),
+            other => panic!("неожиданное событие {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_newest_events_and_counts_dropped() {
+        let (tx, mut rx, stats) = queue(2, SlowClientPolicy::DropOldest, 0);
+        for n in 1..=4 {
+            assert_eq!(tx.send(event(n)).await, Ok(()));
+        }
+        assert_eq!(rx.take_dropped(), 2);
+        assert_eq!(rx.take_dropped(), 0);
+        assert_eq!(stats.dropped_events(), 2);
+        assert_eq!(recv_text(&mut rx).await.as_deref(), Some("3"));
+        assert_eq!(recv_text(&mut rx).await.as_deref(), Some("4"));
+        assert_eq!(stats.slow_disconnects(), 0);
+    }
+
+    #[tokio::test]
+    async fn drop_client_disconnects_on_overflow() {
+        let (tx, mut rx, stats) = queue(2, SlowClientPolicy::DropClient, 0);
+        let slow_disconnect = rx.slow_disconnect();
+        assert_eq!(tx.send(event(1)).await, Ok(()));
+        assert_eq!(tx.send(event(2)).await, Ok(()));
+        assert_eq!(tx.send(event(3)).await, Err(SendError::TooSlow));
+        assert_eq!(tx.send(event(4)).await, Err(SendError::Closed));
+        tokio::time::timeout(Duration::from_secs(1), slow_disconnect).await.unwrap();
+        // Недоставленные события выбрасываются сразу.
+        assert_eq!(recv_text(&mut rx).await, None);
+        assert_eq!(stats.slow_disconnects(), 1);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_reader_before_disconnecting() {
+        let (tx, mut rx, stats) = queue(1, SlowClientPolicy::Block, 500);
+        tx.send(event(1)).await.unwrap();
+        let reader = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let first = recv_text(&mut rx).await;
+            (first, rx)
+        });
+        assert_eq!(tx.send(event(2)).await, Ok(()));
+        let (first, mut rx) = reader.await.unwrap();
+        assert_eq!(first.as_deref(), Some("1"));
+        assert_eq!(recv_text(&mut rx).await.as_deref(), Some("2"));
+        assert_eq!(stats.slow_disconnects(), 0);
+
+        tx.send(event(3)).await.unwrap();
+        let started = Instant::now();
+        assert_eq!(tx.send(event(4)).await, Err(SendError::TooSlow));
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert_eq!(stats.slow_disconnects(), 1);
+    }
+
+    #[tokio::test]
+    async fn close_with_delivers_final_event_even_when_full() {
+        let (tx, mut rx, _) = queue(1, SlowClientPolicy::DropClient, 0);
+        tx.send(event(1)).await.unwrap();
+        tx.close_with(event(2));
+        assert_eq!(tx.send(event(3)).await, Err(SendError::Closed));
+        assert_eq!(recv_text(&mut rx).await.as_deref(), Some("1"));
+        assert_eq!(recv_text(&mut rx).await.as_deref(), Some("2"));
+        assert_eq!(recv_text(&mut rx).await, None);
+    }
+
+    #[tokio::test]
+    async fn dropped_receiver_closes_queue_and_weak_sender_identifies_it() {
+        let (tx, rx, _) = queue(4, SlowClientPolicy::Block, 1000);
+        let (other, _other_rx, _) = queue(4, SlowClientPolicy::Block, 1000);
+        let weak = tx.downgrade();
+        assert!(tx.same_queue(&weak));
+        assert!(!other.same_queue(&weak));
+        drop(rx);
+        assert_eq!(tx.send(event(1)).await, Err(SendError::Closed));
+        drop(tx);
+        assert!(weak.upgrade().is_none());
+    }
+}
@@ -0,0 +1,76 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::rngs::OsRng;
+use subtle::ConstantTimeEq;
+
+// Хеши хранятся в формате PHC ("$argon2id$v=19$..."), всё остальное считается
+// паролем в открытом виде из старых версий users.txt.
+const HASH_PREFIX: &str = "$argon2";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Valid,
+    // Пароль верный, но хранится в открытом виде и его нужно перехешировать.
+    ValidLegacy,
+    Invalid,
+}
+
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with(HASH_PREFIX)
+}
+
+// Argon2id с параметрами по умолчанию и случайной солью.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("Ошибка хеширования пароля: {}", e))
+}
+
+pub fn verify_password(stored: &str, password: &str) -> PasswordCheck {
+    if !is_hashed(stored) {
+        return if bool::from(stored.as_bytes().ct_eq(password.as_bytes())) {
+            PasswordCheck::ValidLegacy
+        } else {
+            PasswordCheck::Invalid
+        };
+    }
+    // Сравнение хешей внутри verify_password выполняется за постоянное время.
+    match PasswordHash::new(stored) {
+        Ok(hash) if Argon2::default().verify_password(password.as_bytes(), &hash).is_ok() => PasswordCheck::Valid,
+        _ => PasswordCheck::Invalid,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_password_verifies_only_with_the_right_password() {
+        let stored = hash_password("секрет").unwrap();
+        assert!(is_hashed(&stored));
+        assert!(stored.starts_with("$argon2id$"));
+        assert_eq!(verify_password(&stored, "секрет"), PasswordCheck::Valid);
+        assert_eq!(verify_password(&stored, "секрет2"), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn same_password_gets_a_different_salt() {
+        assert_ne!(hash_password("pw").unwrap(), hash_password("pw").unwrap());
+    }
+
+    #[test]
+    fn legacy_plaintext_password_asks_for_rehash() {
+        assert!(!is_hashed("old-password"));
+        assert_eq!(verify_password("old-password", "old-password"), PasswordCheck::ValidLegacy);
+        assert_eq!(verify_password("old-password", "old-passwor"), PasswordCheck::Invalid);
+        assert_eq!(verify_password("old-password", ""), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn malformed_hash_is_rejected() {
+        assert_eq!(verify_password("$argon2id$garbage", "$argon2id$garbage"), PasswordCheck::Invalid);
+    }
+}
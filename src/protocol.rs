@@ -0,0 +1,276 @@
+use crate::event::ServerEvent;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+
+// Строка, которую клиент отправляет до авторизации, чтобы перейти в режим JSON Lines.
+pub const JSON_MODE_SWITCH: &str = "/json";
+
+// Соединение с клиентом: обычный TCP или TLS поверх него.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+pub type ClientStream = Box<dyn AsyncStream>;
+pub type ClientReader = BufReader<ReadHalf<ClientStream>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolMode {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum ClientCommand {
+    Help,
+    List,
+    Pm {
+        nick: String,
+        #[serde(with = "hex::serde")]
+        public_key: Vec<u8>,
+    },
+    Accept {
+        nick: Option<String>,
+        #[serde(with = "hex::serde")]
+        public_key: Vec<u8>,
+    },
+    Reject { nick: Option<String> },
+    Requests,
+    Cancel { nick: Option<String> },
+    Switch { nick: Option<String> },
+    Chats,
+    Exit,
+    Join { room: String },
+    Leave,
+    Rooms,
+    Who { room: Option<String> },
+    History { limit: Option<usize> },
+    Say { text: String },
+    Dm { to: String, text: String },
+    Encrypted {
+        to: String,
+        #[serde(with = "hex::serde")]
+        nonce: Vec<u8>,
+        #[serde(with = "hex::serde")]
+        ciphertext: Vec<u8>,
+    },
+    GroupCreate {
+        group: String,
+        #[serde(with = "hex::serde")]
+        public_key: Vec<u8>,
+    },
+    GroupInvite { group: String, nick: String },
+    GroupAccept {
+        group: String,
+        #[serde(with = "hex::serde")]
+        public_key: Vec<u8>,
+    },
+    GroupDecline { group: String },
+    GroupLeave { group: String },
+    GroupList,
+    GroupKey { group: String, epoch: u64, keys: Vec<GroupKeyShare> },
+    GroupMsg {
+        group: String,
+        epoch: u64,
+        #[serde(with = "hex::serde")]
+        nonce: Vec<u8>,
+        #[serde(with = "hex::serde")]
+        ciphertext: Vec<u8>,
+    },
+    Kick { nick: String, reason: Option<String> },
+    Ban { nick: String, reason: Option<String> },
+    Unban { nick: String },
+    Mute { nick: String, seconds: u64 },
+    Unmute { nick: String },
+    Reload,
+    LogLevel { level: Option<String> },
+    Answer { value: String },
+    // Только для текстового режима: строка без команды, смысл которой зависит от состояния клиента.
+    #[serde(skip)]
+    Line(String),
+    #[serde(skip)]
+    Unknown(String),
+}
+
+// Ключ группы для одного участника, зашифрованный общим ключом владельца и этого участника.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupKeyShare {
+    pub nick: String,
+    #[serde(with = "hex::serde")]
+    pub nonce: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    pub ciphertext: Vec<u8>,
+}
+
+// Длительность вида "90", "90s", "15m", "2h" или "1d" в секундах.
+fn parse_duration(text: &str) -> Option<u64> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+impl ClientCommand {
+    pub fn parse_text(line: &str) -> ClientCommand {
+        let Some(command_line) = line.strip_prefix('/') else {
+            return ClientCommand::Line(line.to_string());
+        };
+        let mut parts = command_line.splitn(2, ' ');
+        let command = parts.next().unwrap_or("").to_lowercase();
+        let args = parts.next().unwrap_or("").trim();
+        let words: Vec<&str> = args.split_whitespace().collect();
+        let word = |i: usize| words.get(i).copied().unwrap_or("");
+        // Некорректный hex превращается в пустое значение, его длину проверит сервер.
+        let hex_word = |i: usize| hex::decode(word(i)).unwrap_or_default();
+        let rest = |i: usize| Some(words.get(i..).unwrap_or_default().join(" ")).filter(|text| !text.is_empty());
+
+        match command.as_str() {
+            "help" => ClientCommand::Help,
+            "list" => ClientCommand::List,
+            "pm" => ClientCommand::Pm { nick: word(0).to_string(), public_key: hex_word(1) },
+            // "/accept <ключ>" принимает самый старый запрос, "/accept <ник> <ключ>" — запрос от <ник>.
+            "accept" if words.len() > 1 => ClientCommand::Accept { nick: Some(word(0).to_string()), public_key: hex_word(1) },
+            "accept" => ClientCommand::Accept { nick: None, public_key: hex_word(0) },
+            "encrypted" => ClientCommand::Encrypted {
+                to: word(0).to_string(),
+                nonce: hex_word(1),
+                ciphertext: hex_word(2),
+            },
+            "reject" => ClientCommand::Reject { nick: words.first().map(|nick| nick.to_string()) },
+            "requests" => ClientCommand::Requests,
+            "cancel" => ClientCommand::Cancel { nick: words.first().map(|nick| nick.to_string()) },
+            "switch" => ClientCommand::Switch { nick: words.first().map(|nick| nick.to_string()) },
+            "chats" => ClientCommand::Chats,
+            "join" => ClientCommand::Join { room: word(0).to_string() },
+            "leave" => ClientCommand::Leave,
+            "rooms" => ClientCommand::Rooms,
+            // Нечисловой аргумент превращается в 0, сервер ответит подсказкой.
+            "history" => ClientCommand::History { limit: words.first().map(|n| n.parse().unwrap_or(0)) },
+            "group" => match word(0).to_lowercase().as_str() {
+                "create" => ClientCommand::GroupCreate { group: word(1).to_string(), public_key: hex_word(2) },
+                "invite" => ClientCommand::GroupInvite { group: word(1).to_string(), nick: word(2).to_string() },
+                "accept" => ClientCommand::GroupAccept { group: word(1).to_string(), public_key: hex_word(2) },
+                "decline" => ClientCommand::GroupDecline { group: word(1).to_string() },
+                "leave" => ClientCommand::GroupLeave { group: word(1).to_string() },
+                "list" => ClientCommand::GroupList,
+                // В текстовом режиме ключ раздаётся по одному участнику: /group key <группа> <эпоха> <ник> <nonce> <шифртекст>
+                "key" => ClientCommand::GroupKey {
+                    group: word(1).to_string(),
+                    epoch: word(2).parse().unwrap_or(0),
+                    keys: vec![GroupKeyShare { nick: word(3).to_string(), nonce: hex_word(4), ciphertext: hex_word(5) }],
+                },
+                "send" => ClientCommand::GroupMsg {
+                    group: word(1).to_string(),
+                    epoch: word(2).parse().unwrap_or(0),
+                    nonce: hex_word(3),
+                    ciphertext: hex_word(4),
+                },
+                _ => ClientCommand::Unknown(format!("group {}", word(0))),
+            },
+            "kick" => ClientCommand::Kick { nick: word(0).to_string(), reason: rest(1) },
+            "ban" => ClientCommand::Ban { nick: word(0).to_string(), reason: rest(1) },
+            "unban" => ClientCommand::Unban { nick: word(0).to_string() },
+            // Некорректная длительность превращается в 0, сервер ответит подсказкой.
+            "mute" => ClientCommand::Mute { nick: word(0).to_string(), seconds: parse_duration(word(1)).unwrap_or(0) },
+            "unmute" => ClientCommand::Unmute { nick: word(0).to_string() },
+            "reload" => ClientCommand::Reload,
+            "loglevel" => ClientCommand::LogLevel { level: words.first().map(|level| level.to_string()) },
+            "who" => ClientCommand::Who { room: words.first().map(|room| room.to_string()) },
+            _ => ClientCommand::Unknown(command),
+        }
+    }
+
+    pub fn parse_json(line: &str) -> Result<ClientCommand, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+
+    pub fn to_json_line(&self) -> Result<String, serde_json::Error> {
+        Ok(format!("{}\n", serde_json::to_string(self)?))
+    }
+}
+
+// Пишущая половина сокета вместе с выбранным режимом протокола.
+#[derive(Clone)]
+pub struct ClientWriter {
+    inner: Arc<Mutex<WriteHalf<ClientStream>>>,
+    json: Arc<AtomicBool>,
+}
+
+impl ClientWriter {
+    pub fn new(writer: WriteHalf<ClientStream>) -> Self {
+        ClientWriter {
+            inner: Arc::new(Mutex::new(writer)),
+            json: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn mode(&self) -> ProtocolMode {
+        if self.json.load(Ordering::Relaxed) {
+            ProtocolMode::Json
+        } else {
+            ProtocolMode::Text
+        }
+    }
+
+    pub fn set_mode(&self, mode: ProtocolMode) {
+        self.json.store(mode == ProtocolMode::Json, Ordering::Relaxed);
+    }
+
+    pub fn parse_command(&self, line: &str) -> Result<ClientCommand, serde_json::Error> {
+        match self.mode() {
+            ProtocolMode::Text => Ok(ClientCommand::parse_text(line)),
+            ProtocolMode::Json => ClientCommand::parse_json(line),
+        }
+    }
+
+    pub async fn send(&self, event: &ServerEvent) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let line = match self.mode() {
+            ProtocolMode::Text => event.render_text(),
+            ProtocolMode::Json => event.render_json(),
+        };
+        let mut writer_guard = self.inner.lock().await;
+        writer_guard.write_all(line.as_bytes()).await?;
+        writer_guard.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_accepts_units_and_bare_seconds() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("90s"), Some(90));
+        assert_eq!(parse_duration("15m"), Some(15 * 60));
+        assert_eq!(parse_duration("2h"), Some(2 * 60 * 60));
+        assert_eq!(parse_duration("1d"), Some(24 * 60 * 60));
+    }
+
+    #[test]
+    fn parse_duration_rejects_malformed_input() {
+        for text in ["", "m", "15x", "1.5h", "-5m", "15 m", "10mm"] {
+            assert_eq!(parse_duration(text), None, "{:?}", text);
+        }
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX)), None);
+    }
+
+    #[test]
+    fn mute_command_carries_duration_in_seconds() {
+        assert_eq!(ClientCommand::parse_text("/mute bob 15m"), ClientCommand::Mute { nick: "bob".to_string(), seconds: 900 });
+        assert_eq!(ClientCommand::parse_text("/mute bob soon"), ClientCommand::Mute { nick: "bob".to_string(), seconds: 0 });
+    }
+}
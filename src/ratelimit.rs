@@ -0,0 +1,227 @@
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    // Средний темп для каждого вида действий (0 — без ограничения) и сколько действий можно сделать подряд.
+    pub messages_per_minute: u32,
+    pub direct_per_minute: u32,
+    pub requests_per_minute: u32,
+    pub burst: u32,
+    // После стольких отклонённых подряд действий клиент на время лишается права писать,
+    pub throttle_after: u32,
+    pub throttle_seconds: u64,
+    // а после стольких — отключается.
+    pub disconnect_after: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            messages_per_minute: 30,
+            direct_per_minute: 30,
+            requests_per_minute: 6,
+            burst: 5,
+            throttle_after: 5,
+            throttle_seconds: 30,
+            disconnect_after: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    // Сообщения в комнаты.
+    Message,
+    // Личные, зашифрованные и групповые сообщения.
+    Direct,
+    // Запросы на личный чат и приглашения в группы.
+    Request,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    Limited { retry_after: Duration },
+    Throttled { remaining: Duration },
+    Disconnect,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32, burst: u32) -> Self {
+        let capacity = f64::from(burst.max(1));
+        TokenBucket { capacity, tokens: capacity, per_second: f64::from(per_minute) / 60.0, updated: Instant::now() }
+    }
+
+    // Берёт жетон или возвращает, сколько ждать следующего.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        if self.per_second <= 0.0 {
+            return Ok(());
+        }
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.per_second))
+    }
+}
+
+// Ограничение частоты действий одного подключения: отдельное ведро жетонов на каждый вид действий
+// и общий счётчик отклонённых подряд действий, по которому клиента притормаживают и отключают.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    messages: TokenBucket,
+    direct: TokenBucket,
+    requests: TokenBucket,
+    rejected_in_row: u32,
+    throttled_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            config: config.clone(),
+            messages: TokenBucket::new(config.messages_per_minute, config.burst),
+            direct: TokenBucket::new(config.direct_per_minute, config.burst),
+            requests: TokenBucket::new(config.requests_per_minute, config.burst),
+            rejected_in_row: 0,
+            throttled_until: None,
+        }
+    }
+
+    pub fn check(&mut self, action: Action) -> Verdict {
+        if !self.config.enabled {
+            return Verdict::Allowed;
+        }
+        let now = Instant::now();
+        let taken = match self.throttled_until.filter(|until| *until > now) {
+            Some(until) => Err(Verdict::Throttled { remaining: until - now }),
+            None => {
+                let bucket = match action {
+                    Action::Message => &mut self.messages,
+                    Action::Direct => &mut self.direct,
+                    Action::Request => &mut self.requests,
+                };
+                bucket.take(now).map_err(|retry_after| Verdict::Limited { retry_after })
+            }
+        };
+        let verdict = match taken {
+            Ok(()) => {
+                self.rejected_in_row = 0;
+                return Verdict::Allowed;
+            }
+            Err(verdict) => verdict,
+        };
+
+        self.rejected_in_row += 1;
+        if self.config.disconnect_after > 0 && self.rejected_in_row >= self.config.disconnect_after {
+            return Verdict::Disconnect;
+        }
+        if self.config.throttle_after > 0 && self.rejected_in_row == self.config.throttle_after {
+            let remaining = Duration::from_secs(self.config.throttle_seconds);
+            self.throttled_until = Some(now + remaining);
+            return Verdict::Throttled { remaining };
+        }
+        verdict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(per_minute: u32, burst: u32, throttle_after: u32, disconnect_after: u32) -> RateLimitConfig {
+        RateLimitConfig {
+            enabled: true,
+            messages_per_minute: per_minute,
+            direct_per_minute: per_minute,
+            requests_per_minute: per_minute,
+            burst,
+            throttle_after,
+            throttle_seconds: 30,
+            disconnect_after,
+        }
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refills_at_rate() {
+        let mut bucket = TokenBucket::new(60, 3);
+        let start = bucket.updated;
+        for _ in 0..3 {
+            assert_eq!(bucket.take(start), Ok(()));
+        }
+        let retry_after = bucket.take(start).unwrap_err();
+        assert!((retry_after.as_secs_f64() - 1.0).abs() < 1e-6, "{:?}", retry_after);
+
+        assert!(bucket.take(start + Duration::from_millis(500)).is_err());
+        assert_eq!(bucket.take(start + Duration::from_secs(1)), Ok(()));
+        // Долгий простой не копит жетонов больше, чем burst.
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(later), Ok(()));
+        }
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[test]
+    fn zero_rate_means_unlimited() {
+        let mut bucket = TokenBucket::new(0, 1);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(bucket.take(now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn actions_have_separate_buckets() {
+        let mut limiter = RateLimiter::new(&config(1, 1, 0, 0));
+        assert_eq!(limiter.check(Action::Message), Verdict::Allowed);
+        assert!(matches!(limiter.check(Action::Message), Verdict::Limited { .. }));
+        assert_eq!(limiter.check(Action::Direct), Verdict::Allowed);
+        assert_eq!(limiter.check(Action::Request), Verdict::Allowed);
+    }
+
+    #[test]
+    fn repeated_rejections_throttle_then_disconnect() {
+        let mut limiter = RateLimiter::new(&config(1, 1, 2, 4));
+        assert_eq!(limiter.check(Action::Message), Verdict::Allowed);
+        assert!(matches!(limiter.check(Action::Message), Verdict::Limited { .. }));
+        assert_eq!(limiter.check(Action::Message), Verdict::Throttled { remaining: Duration::from_secs(30) });
+        // Пока клиент приторможен, отклоняются и действия с полными вёдрами.
+        assert!(matches!(limiter.check(Action::Direct), Verdict::Throttled { .. }));
+        assert_eq!(limiter.check(Action::Direct), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn allowed_action_resets_rejection_streak() {
+        let mut limiter = RateLimiter::new(&config(1, 1, 2, 0));
+        assert_eq!(limiter.check(Action::Message), Verdict::Allowed);
+        assert!(matches!(limiter.check(Action::Message), Verdict::Limited { .. }));
+        assert_eq!(limiter.check(Action::Direct), Verdict::Allowed);
+        assert!(matches!(limiter.check(Action::Message), Verdict::Limited { .. }));
+    }
+
+    #[test]
+    fn disabled_limiter_allows_everything() {
+        let mut limiter = RateLimiter::new(&RateLimitConfig { enabled: false, ..config(1, 1, 1, 1) });
+        for _ in 0..10 {
+            assert_eq!(limiter.check(Action::Message), Verdict::Allowed);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use crate::event::RoomInfo;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+const MAX_NAME_LEN: usize = 32;
+
+// Приводит имя к виду "<prefix>имя" в нижнем регистре. Допустимы латиница, цифры, '-' и '_'.
+// Общие правила для имён комнат и групп.
+pub fn normalize_name(prefix: char, name: &str) -> Option<String> {
+    let bare = name.trim().trim_start_matches(prefix);
+    let valid = !bare.is_empty()
+        && bare.len() <= MAX_NAME_LEN
+        && bare.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| format!("{}{}", prefix, bare.to_lowercase()))
+}
+
+// Приводит имя к виду "#имя".
+pub fn normalize_room_name(name: &str) -> Option<String> {
+    normalize_name('#', name)
+}
+
+// Кто в какой комнате. Каждый пользователь находится ровно в одной комнате,
+// пустые комнаты удаляются, кроме комнаты по умолчанию.
+#[derive(Debug)]
+pub struct RoomRegistry {
+    default_room: String,
+    members: BTreeMap<String, BTreeSet<String>>,
+    current: HashMap<String, String>,
+}
+
+impl RoomRegistry {
+    pub fn new(default_room: String) -> Self {
+        let mut members = BTreeMap::new();
+        members.insert(default_room.clone(), BTreeSet::new());
+        RoomRegistry { default_room, members, current: HashMap::new() }
+    }
+
+    pub fn default_room(&self) -> &str {
+        &self.default_room
+    }
+
+    pub fn room_of(&self, nick: &str) -> Option<&str> {
+        self.current.get(nick).map(String::as_str)
+    }
+
+    // Переводит пользователя в комнату и возвращает комнату, из которой он ушёл.
+    pub fn join(&mut self, nick: &str, room: &str) -> Option<String> {
+        let previous = self.remove(nick);
+        self.members.entry(room.to_string()).or_default().insert(nick.to_string());
+        self.current.insert(nick.to_string(), room.to_string());
+        previous
+    }
+
+    pub fn remove(&mut self, nick: &str) -> Option<String> {
+        let room = self.current.remove(nick)?;
+        if let Some(members) = self.members.get_mut(&room) {
+            members.remove(nick);
+            if members.is_empty() && room != self.default_room {
+                self.members.remove(&room);
+            }
+        }
+        Some(room)
+    }
+
+    pub fn members(&self, room: &str) -> Option<Vec<String>> {
+        self.members.get(room).map(|members| members.iter().cloned().collect())
+    }
+
+    pub fn list(&self) -> Vec<RoomInfo> {
+        self.members
+            .iter()
+            .map(|(name, members)| RoomInfo { name: name.clone(), members: members.len() })
+            .collect()
+    }
+}
@@ -0,0 +1,333 @@
+use crate::client::handle_client;
+use crate::config::ServerConfig;
+use crate::groups::GroupRegistry;
+use crate::history::MessageHistory;
+use crate::inbox::OfflineInbox;
+use crate::event::{ReloadReport, ServerEvent};
+use crate::log::{log_message, with_log_context, LogLevel, Logger};
+use crate::message::{broadcast_message, Tx};
+use crate::moderation::Moderation;
+use crate::outbound::OutboundStats;
+use crate::protocol::ClientStream;
+use crate::rooms::{normalize_room_name, RoomRegistry};
+use crate::tls::load_acceptor;
+use crate::users::load_users;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Mutex};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_rustls::TlsAcceptor;
+
+// Сколько ждать завершения TLS-рукопожатия, прежде чем закрыть подключение.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Сколько при остановке ждать, пока подключения доотправят события и завершатся.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type UsersDb = Arc<Mutex<HashMap<String, String>>>;
+pub type ConnectedUsers = Arc<Mutex<HashMap<String, Tx>>>;
+pub type Rooms = Arc<Mutex<RoomRegistry>>;
+pub type Inbox = Arc<Mutex<OfflineInbox>>;
+pub type Groups = Arc<Mutex<GroupRegistry>>;
+pub type ModerationList = Arc<Mutex<Moderation>>;
+
+// Общее состояние сервера, которое разделяют все подключения.
+pub struct ServerState {
+    // Меняется при перезагрузке; сессии берут актуальную версию через config().
+    config: RwLock<Arc<ServerConfig>>,
+    pub users_db: UsersDb,
+    pub connected_users: ConnectedUsers,
+    pub rooms: Rooms,
+    pub history: MessageHistory,
+    pub inbox: Inbox,
+    pub groups: Groups,
+    pub moderation: ModerationList,
+    pub outbound_stats: Arc<OutboundStats>,
+    // Свой файл логов у каждого сервера, даже если их несколько в одном процессе.
+    pub logger: Logger,
+    shutting_down: AtomicBool,
+}
+
+impl ServerState {
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Перечитывает файл пользователей и конфигурацию. База пользователей заменяется целиком под блокировкой,
+    // поэтому вход и регистрация видят либо старый, либо новый список.
+    pub async fn reload(&self) -> Result<ReloadReport, Box<dyn Error + Send + Sync>> {
+        self.logger.scope(self.reload_files()).await
+    }
+
+    async fn reload_files(&self) -> Result<ReloadReport, Box<dyn Error + Send + Sync>> {
+        let old_config = self.config();
+        let (new_config, changes) = old_config.merge_reloaded(old_config.reread()?);
+
+        let mut report = ReloadReport { settings: changes.applied, new_connections: changes.new_connections, restart_required: changes.restart_required, ..ReloadReport::default() };
+        {
+            let mut users_guard = self.users_db.lock().await;
+            // Отсутствующий файл load_users создал бы пустым и удалил бы всех пользователей.
+            if !Path::new(&old_config.users_file).exists() {
+                return Err(format!("Файл пользователей {} не найден", old_config.users_file).into());
+            }
+            let users = load_users(&old_config.users_file).await?;
+            for (nick, stored) in users.iter() {
+                match users_guard.get(nick) {
+                    None => report.added.push(nick.clone()),
+                    Some(old) if old != stored => report.changed.push(nick.clone()),
+                    Some(_) => {}
+                }
+            }
+            report.removed = users_guard.keys().filter(|nick| !users.contains_key(*nick)).cloned().collect();
+            *users_guard = users;
+        }
+        report.added.sort();
+        report.removed.sort();
+        report.changed.sort();
+
+        self.logger.set_level(new_config.log_level);
+        self.logger.set_content(new_config.log_content);
+        self.logger.set_file_options(new_config.log_format, new_config.log_rotation.clone()).await;
+        self.inbox.lock().await.set_limit(new_config.inbox_limit);
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(new_config);
+        log_message(LogLevel::Info, "server", &format!("Перезагрузка: {}", report.summary()));
+        if !report.restart_required.is_empty() {
+            log_message(LogLevel::Warn, "server", &format!("Изменения вступят в силу после перезапуска: {}", report.restart_required.join(", ")));
+        }
+        Ok(report)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+}
+
+pub struct ChatServer {
+    state: Arc<ServerState>,
+    tls: Option<TlsAcceptor>,
+}
+
+impl ChatServer {
+    pub async fn new(config: ServerConfig) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let logger = Logger::open(&config.log_file, config.log_format, config.log_rotation.clone()).await?;
+        logger.set_level(config.log_level);
+        logger.set_content(config.log_content);
+        logger.clone().scope(Self::open(config, logger)).await
+    }
+
+    async fn open(config: ServerConfig, logger: Logger) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let users_db = Arc::new(Mutex::new(load_users(&config.users_file).await?));
+        let connected_users = Arc::new(Mutex::new(HashMap::new()));
+        let default_room = normalize_room_name(&config.default_room)
+            .ok_or_else(|| format!("Некорректное имя комнаты по умолчанию: '{}'", config.default_room))?;
+        let rooms = Arc::new(Mutex::new(RoomRegistry::new(default_room)));
+        let history = MessageHistory::open(&config.history_file).await?;
+        let inbox = Arc::new(Mutex::new(OfflineInbox::load(&config.inbox_file, config.inbox_limit).await?));
+        let groups = Arc::new(Mutex::new(GroupRegistry::default()));
+        let moderation = Arc::new(Mutex::new(Moderation::load(&config.moderation_file).await?));
+        let outbound_stats = Arc::new(OutboundStats::default());
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(load_acceptor(Path::new(cert), Path::new(key))?),
+            (None, None) => None,
+            _ => return Err("Для TLS нужно указать и сертификат (tls_cert), и ключ (tls_key)".into()),
+        };
+        Ok(ChatServer {
+            state: Arc::new(ServerState {
+                config: RwLock::new(Arc::new(config)),
+                users_db,
+                connected_users,
+                rooms,
+                history,
+                inbox,
+                groups,
+                moderation,
+                outbound_stats,
+                logger,
+                shutting_down: AtomicBool::new(false),
+            }),
+            tls,
+        })
+    }
+
+    pub fn state(&self) -> Arc<ServerState> {
+        self.state.clone()
+    }
+
+    pub async fn bind(self) -> Result<ServerHandle, Box<dyn Error + Send + Sync>> {
+        let listener = TcpListener::bind(&self.state.config().bind_addr).await?;
+        self.start(listener).await
+    }
+
+    pub async fn start(self, listener: TcpListener) -> Result<ServerHandle, Box<dyn Error + Send + Sync>> {
+        let local_addr = listener.local_addr()?;
+        let transport = if self.tls.is_some() { "TLS" } else { "TCP" };
+        let logger = &self.state.logger;
+        logger.scope(async { log_message(LogLevel::Info, "server", &format!("Сервер запущен на {} ({})", local_addr, transport)) }).await;
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = tokio::spawn(logger.scope(accept_loop(listener, self.tls, self.state.clone(), shutdown_rx)));
+        Ok(ServerHandle { state: self.state, local_addr, shutdown_tx, task })
+    }
+}
+
+// Без TLS сокет отдаётся как есть, иначе сначала выполняется рукопожатие.
+async fn accept_stream(socket: TcpStream, tls: Option<TlsAcceptor>) -> Result<ClientStream, Box<dyn Error + Send + Sync>> {
+    let Some(acceptor) = tls else {
+        return Ok(Box::new(socket));
+    };
+    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => Ok(Box::new(stream)),
+        Ok(Err(e)) => Err(format!("Ошибка TLS-рукопожатия: {}", e).into()),
+        Err(_) => Err("Истекло время TLS-рукопожатия".into()),
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    state: Arc<ServerState>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut clients = JoinSet::new();
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.changed() => break,
+            Some(_) = clients.join_next(), if !clients.is_empty() => {}
+            accepted = listener.accept() => {
+                let (socket, addr) = accepted?;
+                log_message(LogLevel::Info, "server", &format!("Новое подключение: {}", addr));
+
+                if clients.len() >= state.config().max_clients {
+                    log_message(LogLevel::Warn, "server", &format!("Подключение {} отклонено: достигнут лимит клиентов ({}).", addr, state.config().max_clients));
+                    // Рукопожатие TLS может занять время, поэтому отказ отправляется в отдельной задаче.
+                    let tls = tls.clone();
+                    tokio::spawn(async move {
+                        if let Ok(mut stream) = accept_stream(socket, tls).await {
+                            let _ = stream.write_all("Сервер переполнен. Попробуйте подключиться позже.\n".as_bytes()).await;
+                            let _ = stream.shutdown().await;
+                        }
+                    });
+                    continue;
+                }
+
+                let state_clone = state.clone();
+                let tls = tls.clone();
+                let shutdown = shutdown_rx.clone();
+                clients.spawn(state.logger.scope(with_log_context(addr, async move {
+                    let client_addr = addr;
+                    let result = match accept_stream(socket, tls).await {
+                        Ok(stream) => handle_client(stream, state_clone, shutdown).await,
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(_) => {
+                            log_message(LogLevel::Info, "client", &format!("Клиент {} отключился корректно.", client_addr));
+                        },
+                        Err(e) => {
+                            log_message(LogLevel::Error, "server", &format!("Ошибка с клиентом {}: {:?} Клиент отключился с ошибкой.", client_addr, e));
+                        },
+                    }
+                })));
+            }
+        }
+    }
+
+    drop(listener);
+    state.shutting_down.store(true, Ordering::Relaxed);
+    log_message(LogLevel::Info, "server", &format!("Остановка сервера: новые подключения не принимаются, активных подключений: {}.", clients.len()));
+    broadcast_message(&state.connected_users, "", ServerEvent::server_shutdown()).await;
+    // Каждая сессия доотправит свою очередь, завершит личные чаты и закроет соединение.
+    for tx in state.connected_users.lock().await.values() {
+        tx.close();
+    }
+    let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        while clients.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        log_message(LogLevel::Warn, "server", &format!("Не завершились за {} с подключений: {}, они будут закрыты.", SHUTDOWN_TIMEOUT.as_secs(), clients.len()));
+        // Файлы пользователей, ящика и модерации переписываются только под этими блокировками,
+        // поэтому, пока они захвачены, прерванная сессия не оставит файл записанным наполовину.
+        // Порядок тот же, что в сессиях: база, затем ящик, иначе здесь возможна взаимная блокировка.
+        let _users = state.users_db.lock().await;
+        let _inbox = state.inbox.lock().await;
+        let _moderation = state.moderation.lock().await;
+        clients.shutdown().await;
+    }
+    log_message(LogLevel::Info, "server", "Сервер остановлен.");
+    state.logger.flush().await?;
+    Ok(())
+}
+
+pub struct ServerHandle {
+    state: Arc<ServerState>,
+    local_addr: SocketAddr,
+    shutdown_tx: watch::Sender<bool>,
+    task: JoinHandle<Result<(), Box<dyn Error + Send + Sync>>>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn state(&self) -> &Arc<ServerState> {
+        &self.state
+    }
+
+    pub async fn connected_users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self.state.connected_users.lock().await.keys().cloned().collect();
+        users.sort();
+        users
+    }
+
+    pub fn outbound_stats(&self) -> &OutboundStats {
+        &self.state.outbound_stats
+    }
+
+    // Сколько записей лога этого сервера не попало в файл.
+    pub fn dropped_log_records(&self) -> u64 {
+        self.state.logger.dropped_records()
+    }
+
+    pub async fn registered_users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self.state.users_db.lock().await.keys().cloned().collect();
+        users.sort();
+        users
+    }
+
+    // Работает, пока сервер не завершится сам или не сработает signal, после чего останавливает его.
+    pub async fn run_until(mut self, signal: impl Future<Output = ()>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        tokio::select! {
+            res = &mut self.task => return match res {
+                Ok(res) => res,
+                Err(e) => Err(e.into()),
+            },
+            _ = signal => {}
+        }
+        self.shutdown().await
+    }
+
+    pub async fn reload(&self) -> Result<ReloadReport, Box<dyn Error + Send + Sync>> {
+        self.state.reload().await
+    }
+
+    pub async fn shutdown(self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let _ = self.shutdown_tx.send(true);
+        self.wait().await
+    }
+
+    pub async fn wait(self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self.task.await {
+            Ok(res) => res,
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error + Send + Sync>> {
+    let file = File::open(path).map_err(|e| format!("Не удалось открыть сертификат {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Ошибка чтения сертификата {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("В файле {} нет сертификатов", path.display()).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Box<dyn Error + Send + Sync>> {
+    let file = File::open(path).map_err(|e| format!("Не удалось открыть ключ {}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("Ошибка чтения ключа {}: {}", path.display(), e))?
+        .ok_or_else(|| format!("В файле {} нет закрытого ключа", path.display()).into())
+}
+
+// Серверная сторона: сертификат и закрытый ключ в формате PEM.
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, Box<dyn Error + Send + Sync>> {
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Клиентская сторона: доверяем только сертификатам из указанного файла (например, самоподписанному).
+pub fn load_connector(ca_path: &Path) -> Result<TlsConnector, Box<dyn Error + Send + Sync>> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots.add(cert)?;
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+pub fn server_name(name: &str) -> Result<ServerName<'static>, Box<dyn Error + Send + Sync>> {
+    ServerName::try_from(name.to_string()).map_err(|e| format!("Некорректное имя сервера '{}': {}", name, e).into())
+}
+
+// Самоподписанная пара для локального использования. Не для боевых серверов.
+pub fn generate_self_signed(
+    cert_path: &Path,
+    key_path: &Path,
+    names: Vec<String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let certified = rcgen::generate_simple_self_signed(names)?;
+    // Ключ пишется первым: если он уже есть, сертификат к нему тоже остаётся нетронутым.
+    let mut key_file = create_key_file(key_path).map_err(|e| match e.kind() {
+        ErrorKind::AlreadyExists => format!("Ключ {} уже существует: удалите его или укажите другой путь", key_path.display()),
+        _ => format!("Не удалось записать ключ {}: {}", key_path.display(), e),
+    })?;
+    key_file
+        .write_all(certified.key_pair.serialize_pem().as_bytes())
+        .map_err(|e| format!("Не удалось записать ключ {}: {}", key_path.display(), e))?;
+    std::fs::write(cert_path, certified.cert.pem())
+        .map_err(|e| format!("Не удалось записать сертификат {}: {}", cert_path.display(), e))?;
+    Ok(())
+}
+
+// Закрытый ключ доступен только владельцу и никогда не перезаписывается.
+fn create_key_file(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
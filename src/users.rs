@@ -3,8 +3,18 @@ use std::error::Error;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::fs::{File as TokioFile, OpenOptions as TokioOpenOptions};
 use std::path::Path;
-use crate::log::log_message;
-use colored::Color;
+use crate::log::{log_message, LogLevel};
+use crate::password::is_hashed;
+
+pub const MAX_NICK_LEN: usize = 32;
+
+// Никнейм хранится в users.txt как "ник:хеш" по строке на пользователя, поэтому в нём
+// не может быть ':', пробелов и управляющих символов.
+pub fn is_valid_nick(nick: &str) -> bool {
+    !nick.is_empty()
+        && nick.chars().count() <= MAX_NICK_LEN
+        && nick.chars().all(|c| c != ':' && !c.is_whitespace() && !c.is_control())
+}
 
 pub async fn load_users(path: &str) -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
     let mut users = HashMap::new();
@@ -12,7 +22,7 @@ pub async fn load_users(path: &str) -> Result<HashMap<String, String>, Box<dyn E
 
     if !path_obj.exists() {
         TokioFile::create(path).await?;
-        log_message("Info", &format!("Создан пустой файл пользователей: {}", path), Color::Blue).await?;
+        log_message(LogLevel::Info, "users", &format!("Создан пустой файл пользователей: {}", path));
         return Ok(users);
     }
 
@@ -25,21 +35,65 @@ pub async fn load_users(path: &str) -> Result<HashMap<String, String>, Box<dyn E
         if parts.len() == 2 {
             users.insert(parts[0].to_string(), parts[1].to_string());
         } else if !line.trim().is_empty() {
-            log_message("WARNING", &format!("Неверный формат строки в users.txt: {}", line), Color::Red).await?;
+            log_message(LogLevel::Warn, "users", &format!("Неверный формат строки в {}: {}", path, line));
         }
     }
-    log_message("Info", &format!("Загружено {} пользователей из {}", users.len(), path), Color::Green).await?;
+    log_message(LogLevel::Info, "users", &format!("Загружено {} пользователей из {}", users.len(), path));
+    let legacy_count = users.values().filter(|stored| !is_hashed(stored)).count();
+    if legacy_count > 0 {
+        log_message(LogLevel::Warn, "users", &format!("Паролей в открытом виде: {}. Они будут захешированы при следующем входе пользователей.", legacy_count));
+    }
     Ok(users)
 }
 
-pub async fn add_user_to_file(path: &str, username: &str, password: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+pub async fn add_user_to_file(path: &str, username: &str, password_hash: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Иначе ник с переводом строки допишет в файл чужую запись.
+    if !is_valid_nick(username) {
+        return Err(format!("Недопустимый никнейм {:?}", username).into());
+    }
     let mut file = TokioOpenOptions::new()
         .append(true)
         .create(true)
         .open(path)
         .await?;
-    file.write_all(format!("{}:{}\n", username, password).as_bytes()).await?;
+    file.write_all(format!("{}:{}\n", username, password_hash).as_bytes()).await?;
     file.flush().await?;
-    log_message("Auth", &format!("Пользователь '{}' зарегистрирован и добавлен в файл.", username), Color::Green).await?;
+    log_message(LogLevel::Info, "auth", &format!("Пользователь '{}' зарегистрирован и добавлен в файл.", username));
     Ok(())
-}
\ No newline at end of file
+}
+
+// Заменяет сохранённый пароль пользователя, остальные строки файла не меняются.
+// Файл пишется во временный и переименовывается, чтобы не потерять его при сбое.
+pub async fn update_user_in_file(path: &str, username: &str, password_hash: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let content = tokio::fs::read_to_string(path).await?;
+    let mut updated = String::with_capacity(content.len());
+    for line in content.lines() {
+        match line.trim().split_once(':') {
+            Some((name, _)) if name == username => updated.push_str(&format!("{}:{}", username, password_hash)),
+            _ => updated.push_str(line),
+        }
+        updated.push('\n');
+    }
+
+    let tmp_path = format!("{}.tmp", path);
+    tokio::fs::write(&tmp_path, updated).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    log_message(LogLevel::Info, "auth", &format!("Пароль пользователя '{}' обновлён в файле.", username));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nick_validation_rejects_separators_and_control_characters() {
+        assert!(is_valid_nick("alice"));
+        assert!(is_valid_nick("Алиса_1"));
+        assert!(is_valid_nick(&"a".repeat(MAX_NICK_LEN)));
+        for nick in ["", "zz\nroot", "a\rb", "two words", "root:hash", "tab\tnick", "nul\0", "\u{1b}[31m"] {
+            assert!(!is_valid_nick(nick), "{:?}", nick);
+        }
+        assert!(!is_valid_nick(&"a".repeat(MAX_NICK_LEN + 1)));
+    }
+}
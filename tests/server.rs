@@ -0,0 +1,231 @@
+use kursovik::e2e::KeyPair;
+use kursovik::event::{PromptField, ServerEvent};
+use kursovik::protocol::{ClientCommand, JSON_MODE_SWITCH};
+use kursovik::{ChatServer, ServerConfig, ServerHandle};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+
+const EVENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Отдельный каталог для файлов каждого теста, чтобы тесты не мешали друг другу.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("kursovik-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+async fn start_server(dir: &Path) -> ServerHandle {
+    start_server_with(dir, |_| {}).await
+}
+
+async fn start_server_with(dir: &Path, configure: impl FnOnce(&mut ServerConfig)) -> ServerHandle {
+    let path = |file: &str| dir.join(file).to_string_lossy().into_owned();
+    let mut config = ServerConfig {
+        users_file: path("users.txt"),
+        log_file: path("server.log"),
+        history_file: path("history.jsonl"),
+        inbox_file: path("inbox.json"),
+        moderation_file: path("moderation.json"),
+        ..ServerConfig::default()
+    };
+    configure(&mut config);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    ChatServer::new(config).await.unwrap().start(listener).await.unwrap()
+}
+
+struct TestClient {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl TestClient {
+    // Подключается в режиме JSON Lines и регистрирует нового пользователя.
+    async fn register(handle: &ServerHandle, nick: &str, password: &str) -> TestClient {
+        let mut client = TestClient::connect(handle, nick, password, true).await;
+        client.wait_for(|event| matches!(event, ServerEvent::RoomJoined { .. })).await;
+        client
+    }
+
+    // Возвращается сразу после входа: сообщения из ящика приходят раньше входа в комнату.
+    async fn login(handle: &ServerHandle, nick: &str, password: &str) -> TestClient {
+        TestClient::connect(handle, nick, password, false).await
+    }
+
+    async fn connect(handle: &ServerHandle, nick: &str, password: &str, new_user: bool) -> TestClient {
+        let (reader, mut writer) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+        writer.write_all(format!("{}\n", JSON_MODE_SWITCH).as_bytes()).await.unwrap();
+        let mut client = TestClient { lines: BufReader::new(reader).lines(), writer };
+        loop {
+            let value = match client.next_event().await {
+                ServerEvent::Prompt { field: PromptField::Nick } => nick,
+                ServerEvent::Prompt { field: PromptField::Password } => password,
+                ServerEvent::Prompt { field: PromptField::Register } => "yes",
+                ServerEvent::AuthSuccess { nick: logged_in, registered } => {
+                    assert_eq!(logged_in, nick);
+                    assert_eq!(registered, new_user);
+                    break;
+                }
+                _ => continue,
+            };
+            client.send(ClientCommand::Answer { value: value.to_string() }).await;
+        }
+        client
+    }
+
+    async fn send(&mut self, command: ClientCommand) {
+        self.writer.write_all(command.to_json_line().unwrap().as_bytes()).await.unwrap();
+    }
+
+    // До перехода в JSON сервер пишет обычный текст: такие строки пропускаются.
+    async fn next_event(&mut self) -> ServerEvent {
+        loop {
+            let line = tokio::time::timeout(EVENT_TIMEOUT, self.lines.next_line())
+                .await
+                .expect("сервер не прислал событие вовремя")
+                .unwrap()
+                .expect("сервер закрыл соединение");
+            if let Ok(event) = serde_json::from_str(&line) {
+                return event;
+            }
+        }
+    }
+
+    async fn wait_for(&mut self, matches: impl Fn(&ServerEvent) -> bool) -> ServerEvent {
+        loop {
+            let event = self.next_event().await;
+            if matches(&event) {
+                return event;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn room_message_reaches_other_client_and_shutdown_notifies_everyone() {
+    let dir = test_dir("room-message");
+    let handle = start_server(&dir).await;
+
+    let mut alice = TestClient::register(&handle, "alice", "alice-password").await;
+    let mut bob = TestClient::register(&handle, "bob", "bob-password").await;
+    assert_eq!(handle.connected_users().await, ["alice", "bob"]);
+
+    alice.send(ClientCommand::Say { text: "привет".to_string() }).await;
+    let event = bob.wait_for(|event| matches!(event, ServerEvent::ChatMessage { .. })).await;
+    let ServerEvent::ChatMessage { room, from, text } = event else { unreachable!() };
+    assert_eq!((room.as_str(), from.as_str(), text.as_str()), ("#general", "alice", "привет"));
+
+    assert_eq!(handle.dropped_log_records(), 0);
+    handle.shutdown().await.unwrap();
+    for client in [&mut alice, &mut bob] {
+        client.wait_for(|event| matches!(event, ServerEvent::SystemNotice { code, .. } if code == "server_shutdown")).await;
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn shutdown_does_not_wait_for_clients_at_login_prompt() {
+    let dir = test_dir("login-shutdown");
+    let handle = start_server(&dir).await;
+
+    let (reader, mut writer) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+    writer.write_all(format!("{}\n", JSON_MODE_SWITCH).as_bytes()).await.unwrap();
+    let mut idle = TestClient { lines: BufReader::new(reader).lines(), writer };
+    // Текстовое приветствие клиент JSON пропускает, поэтому сервер повторяет его после mode_changed.
+    let welcome = idle.wait_for(|event| matches!(event, ServerEvent::Welcome { .. })).await;
+    assert!(matches!(welcome, ServerEvent::Welcome { text } if text == ServerConfig::default().welcome));
+    idle.wait_for(|event| matches!(event, ServerEvent::Prompt { field: PromptField::Nick })).await;
+
+    let started = std::time::Instant::now();
+    handle.shutdown().await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+    idle.wait_for(|event| matches!(event, ServerEvent::SystemNotice { code, .. } if code == "server_shutdown")).await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn each_server_writes_its_own_log_file() {
+    let dirs = [test_dir("own-log-1"), test_dir("own-log-2")];
+    for dir in &dirs {
+        let handle = start_server(dir).await;
+        drop(TestClient::register(&handle, "alice", "alice-password").await);
+        handle.shutdown().await.unwrap();
+    }
+    for dir in &dirs {
+        let log = std::fs::read_to_string(dir.join("server.log")).unwrap();
+        assert!(log.contains("Сервер остановлен."), "{}: {:?}", dir.display(), log);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
+
+#[tokio::test]
+async fn direct_message_to_offline_user_is_delivered_on_login() {
+    let dir = test_dir("offline-dm");
+    let handle = start_server(&dir).await;
+
+    let mut alice = TestClient::register(&handle, "alice", "alice-password").await;
+    drop(TestClient::register(&handle, "bob", "bob-password").await);
+    while handle.connected_users().await != ["alice"] {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    alice.send(ClientCommand::Dm { to: "carol".to_string(), text: "ты тут?".to_string() }).await;
+    alice.wait_for(|event| matches!(event, ServerEvent::Error { code, .. } if code == "user_offline")).await;
+    alice.send(ClientCommand::Dm { to: "bob".to_string(), text: "зайди потом".to_string() }).await;
+    alice.wait_for(|event| matches!(event, ServerEvent::SystemNotice { code, .. } if code == "dm_queued")).await;
+
+    let mut bob = TestClient::login(&handle, "bob", "bob-password").await;
+    let event = bob.wait_for(|event| matches!(event, ServerEvent::OfflineMessages { .. })).await;
+    let ServerEvent::OfflineMessages { messages } = event else { unreachable!() };
+    assert_eq!(messages.len(), 1);
+    assert_eq!((messages[0].from.as_str(), messages[0].text.as_str()), ("alice", "зайди потом"));
+
+    handle.shutdown().await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn unanswered_private_chat_request_expires_for_both_sides() {
+    let dir = test_dir("request-expiry");
+    let handle = start_server_with(&dir, |config| config.private_request_timeout = 1).await;
+
+    let mut alice = TestClient::register(&handle, "alice", "alice-password").await;
+    let mut bob = TestClient::register(&handle, "bob", "bob-password").await;
+    alice.send(ClientCommand::Pm { nick: "bob".to_string(), public_key: KeyPair::generate().public_bytes() }).await;
+    bob.wait_for(|event| matches!(event, ServerEvent::PrivateChatRequest { from, .. } if from == "alice")).await;
+
+    alice.wait_for(|event| matches!(event, ServerEvent::PrivateChatExpired { partner } if partner == "bob")).await;
+    bob.wait_for(|event| matches!(event, ServerEvent::PrivateChatExpired { partner } if partner == "alice")).await;
+    bob.send(ClientCommand::Accept { nick: Some("alice".to_string()), public_key: KeyPair::generate().public_bytes() }).await;
+    bob.wait_for(|event| matches!(event, ServerEvent::Error { code, .. } if code == "no_pending_request")).await;
+
+    handle.shutdown().await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn nick_and_message_with_line_breaks_are_rejected() {
+    let dir = test_dir("line-breaks");
+    let handle = start_server(&dir).await;
+
+    let (reader, mut writer) = TcpStream::connect(handle.local_addr()).await.unwrap().into_split();
+    writer.write_all(format!("{}\n", JSON_MODE_SWITCH).as_bytes()).await.unwrap();
+    let mut intruder = TestClient { lines: BufReader::new(reader).lines(), writer };
+    intruder.wait_for(|event| matches!(event, ServerEvent::Prompt { field: PromptField::Nick })).await;
+    intruder.send(ClientCommand::Answer { value: "zz\nroot".to_string() }).await;
+    intruder.wait_for(|event| matches!(event, ServerEvent::Error { code, .. } if code == "invalid_nick")).await;
+    let users = std::fs::read_to_string(dir.join("users.txt")).unwrap();
+    assert!(!users.contains("root"), "{:?}", users);
+
+    let mut alice = TestClient::register(&handle, "alice", "alice-password").await;
+    alice.send(ClientCommand::Say { text: "привет\nbob: поддельная строка".to_string() }).await;
+    alice.wait_for(|event| matches!(event, ServerEvent::Error { code, .. } if code == "multiline_text")).await;
+    alice.send(ClientCommand::Say { text: "  ".to_string() }).await;
+    alice.wait_for(|event| matches!(event, ServerEvent::Error { code, .. } if code == "empty_message")).await;
+
+    handle.shutdown().await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}